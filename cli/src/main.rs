@@ -1,9 +1,14 @@
 #[macro_use]
 extern crate clap;
 
+use std::collections::HashMap;
 use std::fs;
+use std::io::Write;
+use std::ops::Range;
 
+use plotters::coord::Shift;
 use plotters::prelude::*;
+use readings_probe::format::{self, Kind, Recording, Row, SpanEdge};
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let matches = clap_app!(myapp =>
@@ -14,103 +19,447 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
      (@arg SINGLE_CORE: --("single-core") "Show CPU assuming single thread")
      (@arg FROM: -f --("from") +takes_value "Timestamp (seconds) or event label to start from")
      (@arg TO: -t --("to") +takes_value "Timestamp (secodns) or event label to stop at.")
+     (@setting SubcommandsNegateReqs)
+     (@subcommand convert =>
+      (about: "Converts a readings file (binary or V1) to V2 text")
+      (@arg INPUT: +required "Sets the input file to convert")
+      (@arg OUTPUT: "Sets the output file (defaults to standard output)")
+     )
+     (@subcommand events =>
+      (about: "Prints a table of the events and their fields")
+      (@arg INPUT: +required "Sets the input file to read")
+     )
     )
     .get_matches();
-    plot(matches.value_of("INPUT").unwrap(), &matches)?;
+    if let Some(matches) = matches.subcommand_matches("convert") {
+        convert(
+            matches.value_of("INPUT").unwrap(),
+            matches.value_of("OUTPUT"),
+        )?;
+    } else if let Some(matches) = matches.subcommand_matches("events") {
+        events(matches.value_of("INPUT").unwrap())?;
+    } else {
+        plot(matches.value_of("INPUT").unwrap(), &matches)?;
+    }
+    Ok(())
+}
+
+fn convert(input: &str, output: Option<&str>) -> Result<(), Box<dyn std::error::Error>> {
+    let rec = format::read(std::io::BufReader::new(fs::File::open(input)?))?;
+    let output: Box<dyn std::io::Write> = match output {
+        Some(output) => Box::new(fs::File::create(output)?),
+        None => Box::new(std::io::stdout()),
+    };
+    let mut output = std::io::BufWriter::new(output);
+    format::write_header(&mut output, &rec.header)?;
+    let mut threads = rec.threads.iter().peekable();
+    for row in &rec.rows {
+        while let Some(sample) = threads.next_if(|s| s.time <= row.values[0].as_f64()) {
+            format::write_threads(&mut output, sample)?;
+        }
+        format::write_row(&mut output, row)?;
+    }
+    for sample in threads {
+        format::write_threads(&mut output, sample)?;
+    }
+    if rec.dropped > 0 {
+        format::write_dropped(&mut output, rec.dropped)?;
+    }
+    output.flush()?;
+    Ok(())
+}
+
+fn events(input: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let rec = format::read(std::io::BufReader::new(fs::File::open(input)?))?;
+    let events: Vec<&Row> = rec.rows.iter().filter(|r| !is_heartbeat(r)).collect();
+    let width = events
+        .iter()
+        .map(|r| r.event.as_ref().unwrap().len())
+        .max()
+        .unwrap_or(0)
+        .max(5);
+    let stdout = std::io::stdout();
+    let mut output = stdout.lock();
+    writeln!(
+        output,
+        "{:>10} {:width$} fields",
+        "time",
+        "event",
+        width = width
+    )?;
+    for row in events {
+        write!(
+            output,
+            "{:>10.3} {:width$}",
+            time(row),
+            row.event.as_ref().unwrap(),
+            width = width
+        )?;
+        if let Some(span) = &row.span {
+            write!(output, " {}", span)?;
+        }
+        for (key, value) in &row.fields {
+            write!(output, " {}={}", key, value)?;
+        }
+        writeln!(output)?;
+    }
     Ok(())
 }
 
-fn val<T: std::fmt::Debug + std::str::FromStr>(line: &str, ith: usize) -> T
-where
-    <T as std::str::FromStr>::Err: std::fmt::Debug,
-{
-    let s = line.split_whitespace().nth(ith).unwrap();
-    s.parse().unwrap()
+struct UserDefined<'a> {
+    name: &'a str,
+    kind: Kind,
+    float: bool,
+    points: Vec<(f32, f64)>,
+    min: f64,
+    max: f64,
+    total: f64,
+}
+
+impl<'a> UserDefined<'a> {
+    fn new(rec: &'a Recording, ix: usize) -> UserDefined<'a> {
+        let column = &rec.header.columns[ix];
+        let points = if column.kind == Kind::Counter {
+            // counters are plotted as a per-second rate between heartbeats
+            let heartbeats = rec
+                .rows
+                .iter()
+                .filter(|r| is_heartbeat(r))
+                .map(|r| (time(r), r.values[ix].as_f64()))
+                .collect::<Vec<_>>();
+            heartbeats
+                .windows(2)
+                .filter(|w| w[1].0 > w[0].0)
+                .map(|w| (w[1].0, (w[1].1 - w[0].1) / (w[1].0 - w[0].0) as f64))
+                .collect()
+        } else {
+            series(rec, ix)
+        };
+        let (min, max) = points
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), (_, v)| {
+                (min.min(*v), max.max(*v))
+            });
+        UserDefined {
+            name: &column.name,
+            kind: column.kind,
+            float: column.ty == format::ValueType::F64,
+            points,
+            min,
+            max,
+            total: rec
+                .rows
+                .last()
+                .map(|r| r.values[ix].as_f64())
+                .unwrap_or(0.0),
+        }
+    }
+
+    /// Low end of the plotted range: zero, unless the metric goes negative.
+    fn low(&self) -> f64 {
+        self.min.min(0.0)
+    }
+
+    fn normalize(&self, v: f64) -> f32 {
+        let range = self.max - self.low();
+        if range > 0.0 {
+            ((v - self.low()) / range) as f32
+        } else {
+            0.0
+        }
+    }
+
+    fn format(&self, v: f64) -> String {
+        if self.float {
+            format!("{:.3}", v)
+        } else {
+            format!("{}", v as i64)
+        }
+    }
+
+    fn legend(&self) -> String {
+        if self.kind == Kind::Counter {
+            format!(
+                "{} max:{}/s total:{}",
+                self.name,
+                self.format(self.max),
+                self.format(self.total)
+            )
+        } else if self.low() < 0.0 {
+            format!(
+                "{} min:{} max:{}",
+                self.name,
+                self.format(self.min),
+                self.format(self.max)
+            )
+        } else {
+            format!("{} max:{}", self.name, self.format(self.max))
+        }
+    }
+}
+
+/// A latency histogram, spanning four columns: p50, p90, p99 and max.
+struct Histogram<'a> {
+    name: &'a str,
+    /// Column indices of p50, p90, p99 and max.
+    columns: [usize; 4],
+    max_p99: f64,
+    max: f64,
+}
+
+impl<'a> Histogram<'a> {
+    fn new(rec: &'a Recording, name: &'a str) -> Option<Histogram<'a>> {
+        let mut columns = [0; 4];
+        for (ix, q) in ["p50", "p90", "p99", "max"].iter().enumerate() {
+            columns[ix] = rec.header.column(&format!("{}.{}", name, q))?;
+        }
+        let max_of = |ix: usize| series(rec, ix).iter().fold(0.0, |acc, &(_, v)| v.max(acc));
+        Some(Histogram {
+            name,
+            columns,
+            max_p99: max_of(columns[2]),
+            max: max_of(columns[3]),
+        })
+    }
+
+    /// Points for the given quantile (0 to 3), skipping lines where nothing
+    /// was recorded.
+    fn points(&self, rec: &Recording, q: usize) -> Vec<(f32, f32)> {
+        rec.rows
+            .iter()
+            .filter(|r| r.values[self.columns[3]].as_f64() > 0.0)
+            .map(|r| {
+                let v = r.values[self.columns[q]].as_f64();
+                (time(r), (v / self.max_p99.max(1.0)) as f32)
+            })
+            .collect()
+    }
+
+    fn legend(&self) -> String {
+        format!(
+            "{} p50-p99 max p99:{} max:{}",
+            self.name,
+            format_nanos(self.max_p99),
+            format_nanos(self.max)
+        )
+    }
+}
+
+fn format_nanos(nanos: f64) -> String {
+    if nanos < 1e3 {
+        format!("{}ns", nanos)
+    } else if nanos < 1e6 {
+        format!("{:.1}µs", nanos / 1e3)
+    } else if nanos < 1e9 {
+        format!("{:.1}ms", nanos / 1e6)
+    } else {
+        format!("{:.1}s", nanos / 1e9)
+    }
+}
+
+fn format_count(count: f64) -> String {
+    if count < 1e3 {
+        format!("{}", count)
+    } else if count < 1e6 {
+        format!("{:.1}k", count / 1e3)
+    } else {
+        format!("{:.1}M", count / 1e6)
+    }
+}
+
+fn time(row: &Row) -> f32 {
+    row.values[0].as_f64() as f32
+}
+
+fn series(rec: &Recording, ix: usize) -> Vec<(f32, f64)> {
+    rec.rows
+        .iter()
+        .map(|r| (time(r), r.values[ix].as_f64()))
+        .collect()
 }
 
-fn line<'a, T: std::fmt::Debug + std::str::FromStr>(
-    data: &'a [&'a str],
-    ith: usize,
-) -> impl Iterator<Item = (f32, T)> + 'a
-where
-    <T as std::str::FromStr>::Err: std::fmt::Debug,
-{
-    data.into_iter()
-        .map(move |line| (val(line, 0), val(line, ith)))
+/// A span, reconstructed from its begin and end rows.
+struct Span<'a> {
+    name: &'a str,
+    start: f32,
+    end: Option<f32>,
+    depth: usize,
 }
 
-fn in_time_range(l: &str, start: f32, end: f32) -> bool {
-    let time = val(l, 0);
+/// Pairs span begin and end rows. Spans still open at the end of the file
+/// have no end.
+fn spans(rec: &Recording) -> Vec<Span<'_>> {
+    let mut spans: Vec<Span> = vec![];
+    let mut ids = HashMap::new();
+    for row in &rec.rows {
+        match (row.span, &row.event) {
+            (Some(SpanEdge::Begin { id, parent }), Some(name)) => {
+                let depth = parent
+                    .and_then(|p| ids.get(&p))
+                    .map(|&ix: &usize| spans[ix].depth + 1)
+                    .unwrap_or(0);
+                ids.insert(id, spans.len());
+                spans.push(Span {
+                    name,
+                    start: time(row),
+                    end: None,
+                    depth,
+                });
+            }
+            (Some(SpanEdge::End { id }), _) => {
+                if let Some(&ix) = ids.get(&id) {
+                    spans[ix].end = Some(time(row));
+                }
+            }
+            _ => (),
+        }
+    }
+    spans
+}
+
+/// Per second rate of a counter, over `smooth` rows.
+fn rate(rows: &[&Row], ix: usize, smooth: usize) -> Vec<(f32, f64)> {
+    rows.iter()
+        .skip(smooth)
+        .zip(rows.iter())
+        .map(|(b, a)| {
+            (
+                time(b),
+                (b.values[ix].as_f64() - a.values[ix].as_f64()) / (time(b) - time(a)) as f64,
+            )
+        })
+        .collect()
+}
+
+fn column(rec: &Recording, name: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    rec.header
+        .column(name)
+        .ok_or_else(|| format!("column not found: {}", name).into())
+}
+
+/// Heartbeat lines are the ones without an event label (save for the
+/// very first one).
+fn is_heartbeat(row: &Row) -> bool {
+    match &row.event {
+        None => true,
+        Some(label) => label == "spawned_heartbeat",
+    }
+}
+
+fn in_time_range(row: &Row, start: f32, end: f32) -> bool {
+    let time = time(row);
     start <= time && time <= end
 }
 
-fn tranlate_time_expr(time: &str, lines: &[&str]) -> Result<f32, Box<dyn std::error::Error>> {
-    time.parse::<f32>().or_else(|_| {
-        lines
+fn tranlate_time_expr(time_expr: &str, rec: &Recording) -> Result<f32, Box<dyn std::error::Error>> {
+    time_expr.parse::<f32>().or_else(|_| {
+        rec.rows
             .iter()
-            .find(|l| l.split(" ").last().unwrap() == time)
-            .map(|l| val(l, 0))
-            .ok_or_else(|| format!("label not fond: {}", time).into())
+            .find(|r| r.event.as_ref().map(|e| e == time_expr).unwrap_or(false))
+            .map(time)
+            .ok_or_else(|| format!("label not fond: {}", time_expr).into())
     })
 }
 
 fn plot(data: &str, matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error::Error>> {
     let png = format!("{}.png", data);
-    let data = fs::read(data)?;
-    assert!(data.starts_with(b"#ReadingsV1\n"));
-    let data = String::from_utf8(data)?;
-    let header = data.lines().nth(1).unwrap();
-    let data: Vec<&str> = data.lines().skip(2).collect();
-
-    let mut user_defined = header.split_whitespace().skip(11).collect::<Vec<_>>();
-    user_defined.pop();
-    let user_defined: Vec<(&str, f64)> = user_defined
-        .into_iter()
-        .enumerate()
-        .map(|(ix, name)| {
-            let max = line::<f64>(&*data, ix + 11)
-                .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))
-                .unwrap()
-                .1;
-            (name, max)
-        })
-        .collect();
-    let user_defined_len = user_defined.len();
+    let rec = format::read(std::io::BufReader::new(fs::File::open(data)?))?;
+    if rec.rows.is_empty() {
+        Err("no data to plot")?
+    }
+    if rec.dropped > 0 {
+        eprintln!(
+            "warning: the probe dropped {} samples, the plot may miss events",
+            rec.dropped
+        );
+    }
 
-    let time_start: f32 = tranlate_time_expr(matches.value_of("FROM").unwrap_or("0.0"), &data)?;
+    let mut histograms: Vec<Histogram> = vec![];
+    let mut user_defined: Vec<UserDefined> = vec![];
+    for (ix, c) in rec.header.columns.iter().enumerate() {
+        if c.source != "user" {
+            continue;
+        }
+        if c.kind == Kind::Histogram {
+            if c.name.ends_with(".p50") {
+                histograms.extend(Histogram::new(&rec, c.name.trim_end_matches(".p50")));
+            }
+        } else {
+            user_defined.push(UserDefined::new(&rec, ix));
+        }
+    }
+
+    let time_start: f32 = tranlate_time_expr(matches.value_of("FROM").unwrap_or("0.0"), &rec)?;
     let time_end: f32 = matches
         .value_of("TO")
-        .map(|v| tranlate_time_expr(v, &data))
+        .map(|v| tranlate_time_expr(v, &rec))
         .transpose()?
-        .unwrap_or_else(|| {
-            data.last()
-                .unwrap()
-                .split_whitespace()
-                .nth(0)
-                .unwrap()
-                .parse()
-                .unwrap()
-        });
+        .unwrap_or_else(|| time(rec.rows.last().unwrap()));
 
-    let root = BitMapBackend::new(&*png, (1024, 768)).into_drawing_area();
+    let spans = spans(&rec);
+    let lanes = spans.iter().map(|s| s.depth + 1).max().unwrap_or(0);
+    let lanes_height = if lanes > 0 { 20 * lanes as u32 + 10 } else { 0 };
+    let pressure = [
+        "cpu_some", "cpu_full", "mem_some", "mem_full", "io_some", "io_full",
+    ]
+    .iter()
+    .map(|c| rec.header.column(c))
+    .collect::<Option<Vec<_>>>();
+    let pressure_height = if pressure.is_some() { 200 } else { 0 };
+    let io = ["rchar", "wchar", "read_bytes", "write_bytes"]
+        .iter()
+        .map(|c| rec.header.column(c))
+        .collect::<Option<Vec<_>>>();
+    let io_height = if io.is_some() { 200 } else { 0 };
+    let sched = ["nvcsw", "nivcsw", "threads", "fds"]
+        .iter()
+        .map(|c| rec.header.column(c))
+        .collect::<Option<Vec<_>>>();
+    let sched_height = if sched.is_some() { 200 } else { 0 };
+    // only meaningful with an instrumented allocator
+    let allocs = ["allocs", "deallocs", "reallocs", "live_allocs"]
+        .iter()
+        .map(|c| rec.header.column(c))
+        .collect::<Option<Vec<_>>>()
+        .filter(|allocs| rec.rows.last().unwrap().values[allocs[0]].as_f64() > 0.0);
+    let allocs_height = if allocs.is_some() { 200 } else { 0 };
+    let height = 768 + pressure_height + io_height + sched_height + allocs_height + lanes_height;
+    let root = BitMapBackend::new(&*png, (1024, height)).into_drawing_area();
     root.fill(&WHITE)?;
+    let (root, lower) = root.split_vertically(768);
+    let (pressure_area, lower) = lower.split_vertically(pressure_height);
+    let (io_area, lower) = lower.split_vertically(io_height);
+    let (sched_area, lower) = lower.split_vertically(sched_height);
+    let (allocs_area, timeline) = lower.split_vertically(allocs_height);
 
-    let max_memory = data.iter().map(|l| val::<i64>(l, 3)).max().unwrap();
+    // os and alloc readings may have been disabled in the probe
+    let rsz = rec.header.column("rsz");
+    let memory_limit: Option<i64> = rec
+        .header
+        .meta("cgroup_memory_max")
+        .and_then(|m| m.parse().ok());
+    // keep the cgroup limit and usage in range when there is a limit
+    let mut memory_columns = rsz.into_iter().collect::<Vec<_>>();
+    memory_columns.extend(rec.header.column("heap_peak"));
+    if memory_limit.is_some() {
+        memory_columns.extend(rec.header.column("cgroup_mem"));
+    }
+    let max_memory = memory_columns
+        .iter()
+        .flat_map(|&ix| series(&rec, ix))
+        .fold(memory_limit.unwrap_or(1024) as f64, |acc, (_, v)| {
+            v.max(acc)
+        }) as i64;
     let max_memory_range = 10i64.pow((max_memory as f64).log10() as u32 + 1);
     let mem_magnitude = ((max_memory_range as f64).log10() as usize - 2) / 3 * 3;
     let mem_magnitude_div = 10i64.pow(mem_magnitude as u32);
     let mem_magnitude_suffix = ["", "kB", "MB", "GB", "TB"][mem_magnitude / 3];
 
-    let events = data
+    let events = rec
+        .rows
         .iter()
-        .filter(|l| in_time_range(l, time_start, time_end))
-        .filter(|l| {
-            l.split_whitespace()
-                .nth(11 + user_defined_len)
-                .unwrap_or(&"")
-                .len()
-                > 0
-        })
+        .filter(|r| in_time_range(r, time_start, time_end))
+        .filter(|r| r.event.is_some() && r.span.is_none())
         .collect::<Vec<_>>();
 
     let mut chart = ChartBuilder::on(&root)
@@ -133,21 +482,56 @@ fn plot(data: &str, matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error
         .y_label_formatter(&|&x| format!("{}{}", x / mem_magnitude_div, mem_magnitude_suffix))
         .draw()?;
 
-    for i in 0..events.len() / 2 {
-        let ev_start = val(events[2 * i], 0);
-        let ev_end = val(events[2 * i + 1], 0);
-        chart.plotting_area().draw(&Rectangle::new(
-            [(ev_start, 0.0), (ev_end, 1.0)],
-            RGBColor(200, 200, 200).to_rgba().filled(),
-        ))?;
+    chart.draw_series(events.iter().map(|event| {
+        PathElement::new(
+            vec![(time(event), 0.0), (time(event), 1.0)],
+            &RGBColor(200, 200, 200),
+        )
+    }))?;
+
+    if lanes > 0 {
+        let mut lane_chart = ChartBuilder::on(&timeline)
+            .y_label_area_size(50)
+            .right_y_label_area_size(60)
+            .margin(5)
+            .build_ranged(time_start..time_end, 0f32..lanes as f32)?;
+        let mut names: Vec<&str> = vec![];
+        for span in &spans {
+            let end = span.end.unwrap_or(time_end).min(time_end);
+            let start = span.start.max(time_start);
+            if end < start {
+                continue;
+            }
+            let color_ix = names
+                .iter()
+                .position(|n| *n == span.name)
+                .unwrap_or_else(|| {
+                    names.push(span.name);
+                    names.len() - 1
+                });
+            let color = Palette99::pick(color_ix);
+            // top level spans go in the upper lane
+            let top = (lanes - span.depth) as f32 - 0.1;
+            lane_chart.draw_series(std::iter::once(Rectangle::new(
+                [(start, top), (end, top - 0.8)],
+                color.mix(0.4).filled(),
+            )))?;
+            let coord = lane_chart.as_coord_spec();
+            let width = coord.translate(&(end, top)).0 - coord.translate(&(start, top)).0;
+            if width as usize > 7 * span.name.len() {
+                lane_chart.draw_series(std::iter::once(Text::new(
+                    span.name.to_string(),
+                    (start, top - 0.15),
+                    ("sans-serif", 12).into_font(),
+                )))?;
+            }
+        }
     }
 
     let mut last_x_plotted = 0;
-    for i in 0..events.len() {
+    for (i, event) in events.iter().enumerate() {
         use plotters::style::text_anchor::{HPos, Pos, VPos};
-        let coords = chart
-            .as_coord_spec()
-            .translate(&(val::<f32>(events[i], 0), -0.1));
+        let coords = chart.as_coord_spec().translate(&(time(event), -0.1));
         if i != 0 && coords.0 - last_x_plotted < 10 {
             continue;
         }
@@ -156,84 +540,636 @@ fn plot(data: &str, matches: &clap::ArgMatches) -> Result<(), Box<dyn std::error
         let style = TextStyle::from(("sans-serif", 12).into_font())
             .transform(FontTransform::Rotate270)
             .pos(pos);
-        root.draw_text(events[i].split_whitespace().last().unwrap(), &style, coords)?;
+        root.draw_text(event.event.as_ref().unwrap(), &style, coords)?;
     }
 
-    let hearbeat_series = data
+    let hearbeat_series = rec
+        .rows
         .iter()
-        .filter(|l| in_time_range(l, time_start, time_end))
-        .filter(|l| {
-            ["", "spawned_heartbeat"].contains(
-                &l.split_whitespace()
-                    .nth(11 + user_defined_len)
-                    .unwrap_or(""),
-            )
-        })
+        .filter(|r| in_time_range(r, time_start, time_end))
+        .filter(|r| is_heartbeat(r))
         .collect::<Vec<_>>();
-    let hearbeat: f32 = val::<f32>(hearbeat_series[1], 0) - val::<f32>(hearbeat_series[0], 0);
+    let hearbeat: f32 = rec
+        .header
+        .meta("heartbeat")
+        .and_then(|h| h.parse().ok())
+        .unwrap_or_else(|| time(hearbeat_series[1]) - time(hearbeat_series[0]));
     let cores: usize = if matches.is_present("SINGLE_CORE") {
         1
     } else {
-        val(data[0], 1)
+        rec.rows[0].values[column(&rec, "cores")?].as_f64() as usize
     };
 
     let smooth_cpu = ((0.2 / hearbeat) as usize).max(1);
 
-    let cpu_series = hearbeat_series
-        .iter()
-        .map(|l| val::<f32>(l, 5) + val::<f32>(l, 6))
-        .collect::<Vec<_>>();
-    let cpu_series = cpu_series
-        .iter()
-        .zip(
-            std::iter::repeat(&0.0)
-                .take(smooth_cpu)
-                .chain(cpu_series.iter()),
-        )
-        .enumerate()
-        .map(|(t, (b, a))| {
-            (
-                t as f32 * hearbeat,
-                (b - a) / cores as f32 / hearbeat / smooth_cpu as f32,
-            )
-        })
-        .collect::<Vec<_>>();
+    if let (Some(utime), Some(stime)) = (rec.header.column("utime"), rec.header.column("stime")) {
+        let cpu_series = hearbeat_series
+            .iter()
+            .map(|r| {
+                (
+                    time(r),
+                    (r.values[utime].as_f64() + r.values[stime].as_f64()) as f32,
+                )
+            })
+            .collect::<Vec<_>>();
+        let cpu_series = cpu_series
+            .iter()
+            .skip(smooth_cpu)
+            .zip(cpu_series.iter())
+            .map(|(b, a)| (b.0, (b.1 - a.1) / cores as f32 / (b.0 - a.0)))
+            .collect::<Vec<_>>();
+
+        chart.draw_series(AreaSeries::new(cpu_series, 0.0, &RED.mix(0.15)).border_style(&RED))?;
+    }
 
-    chart.draw_series(AreaSeries::new(cpu_series, 0.0, &RED.mix(0.15)).border_style(&RED))?;
+    if let Some(throttled) = rec.header.column("throttled") {
+        let throttled = rate(&hearbeat_series, throttled, smooth_cpu);
+        if throttled.iter().any(|&(_, v)| v > 0.0) {
+            let orange = RGBColor(230, 120, 0);
+            chart
+                .draw_series(
+                    AreaSeries::new(
+                        throttled
+                            .into_iter()
+                            .map(|(t, v)| (t, (v / cores as f64).min(1.0) as f32)),
+                        0.0,
+                        &orange.mix(0.3),
+                    )
+                    .border_style(&orange),
+                )?
+                .label("cpu throttled")
+                .legend(|(x, y)| {
+                    Rectangle::new(
+                        [(x, y - 4), (x + 20, y + 4)],
+                        RGBColor(230, 120, 0).mix(0.5).filled(),
+                    )
+                });
+        }
+    }
 
-    chart.draw_secondary_series(
-        AreaSeries::new(
-            data.iter()
-                .map(|l| {
-                    (val(l, 0), val::<i64>(l, 9) - val::<i64>(l, 10))
+    if let Some(peak) = rec.header.column("heap_peak") {
+        // each line holds the peak since the previous one: draw it as steps
+        let mut steps = vec![];
+        let mut previous = None;
+        for (t, v) in series(&rec, peak) {
+            steps.push((previous.unwrap_or(t), v as i64));
+            steps.push((t, v as i64));
+            previous = Some(t);
+        }
+        chart
+            .draw_secondary_series(
+                AreaSeries::new(steps, 0, &BLUE.mix(0.1)).border_style(&BLUE.mix(0.5)),
+            )?
+            .label("heap peak")
+            .legend(|(x, y)| Rectangle::new([(x, y - 4), (x + 20, y + 4)], BLUE.mix(0.1).filled()));
+    }
+
+    if let (Some(alloc), Some(free)) = (rec.header.column("alloc"), rec.header.column("free")) {
+        chart.draw_secondary_series(
+            AreaSeries::new(
+                rec.rows.iter().map(|r| {
+                    (
+                        time(r),
+                        r.values[alloc].as_f64() as i64 - r.values[free].as_f64() as i64,
+                    )
                 }),
-            0,
-            &BLUE.mix(0.3),
-        )
-        .border_style(&BLUE),
-    )?;
+                0,
+                &BLUE.mix(0.3),
+            )
+            .border_style(&BLUE),
+        )?;
+    }
 
-    chart.draw_secondary_series(
-        AreaSeries::new(line(&data, 3), 0, &BLACK.mix(0.3)).border_style(&BLACK),
-    )?;
+    if let Some(rsz) = rsz {
+        chart.draw_secondary_series(
+            AreaSeries::new(
+                series(&rec, rsz).into_iter().map(|(t, v)| (t, v as i64)),
+                0,
+                &BLACK.mix(0.3),
+            )
+            .border_style(&BLACK),
+        )?;
+    }
+
+    if let (Some(limit), Some(usage)) = (memory_limit, rec.header.column("cgroup_mem")) {
+        chart
+            .draw_secondary_series(LineSeries::new(
+                vec![(time_start, limit), (time_end, limit)],
+                &MAGENTA,
+            ))?
+            .label("cgroup memory limit")
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &MAGENTA));
+        chart
+            .draw_secondary_series(LineSeries::new(
+                series(&rec, usage).into_iter().map(|(t, v)| (t, v as i64)),
+                &MAGENTA.mix(0.5),
+            ))?
+            .label("cgroup memory")
+            .legend(|(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &MAGENTA.mix(0.5)));
+    }
 
     for (ix, ud) in user_defined.iter().enumerate() {
         chart
             .draw_series(LineSeries::new(
-                data.iter()
-                    .map(|l| (val(l, 0), (val::<f64>(l, 11 + ix) / ud.1) as f32)),
+                ud.points.iter().map(|&(t, v)| (t, ud.normalize(v))),
                 &Palette100::pick(ix),
             ))?
-            .label(format!("{} max:{}", ud.0, ud.1))
+            .label(ud.legend())
             .legend(move |(x, y)| {
                 PathElement::new(vec![(x, y), (x + 20, y)], &Palette100::pick(ix))
             });
     }
 
+    for (ix, histogram) in histograms.iter().enumerate() {
+        let color = Palette100::pick(user_defined.len() + ix);
+        let p50 = histogram.points(&rec, 0);
+        let mut band = histogram.points(&rec, 2);
+        band.extend(p50.iter().rev());
+        chart.draw_series(std::iter::once(Polygon::new(band, &color.mix(0.2))))?;
+        chart
+            .draw_series(LineSeries::new(histogram.points(&rec, 1), &color))?
+            .label(histogram.legend())
+            .legend(move |(x, y)| {
+                Rectangle::new([(x, y - 4), (x + 20, y + 4)], color.mix(0.5).filled())
+            });
+    }
+
     chart
         .configure_series_labels()
         .background_style(&RGBColor(128, 128, 128))
         .draw()?;
 
+    if let Some(pressure) = pressure {
+        let styles = [
+            ("cpu some", (230, 80, 80)),
+            ("cpu full", (150, 0, 0)),
+            ("memory some", (80, 80, 230)),
+            ("memory full", (0, 0, 150)),
+            ("io some", (80, 180, 80)),
+            ("io full", (0, 100, 0)),
+        ];
+        // stalled seconds per second
+        let stalls = pressure
+            .iter()
+            .zip(styles.iter())
+            .map(|(&ix, &(label, color))| Curve {
+                label,
+                color,
+                points: rate(&hearbeat_series, ix, smooth_cpu)
+                    .into_iter()
+                    .map(|(t, v)| (t, (v * 100.0).min(100.0)))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        panel(
+            &pressure_area,
+            "Pressure stalls (% of time)",
+            time_start..time_end,
+            &stalls,
+            &[],
+        )?;
+    }
+
+    if let Some(io) = io {
+        let rates = io
+            .iter()
+            .map(|&ix| rate(&hearbeat_series, ix, smooth_cpu))
+            .collect::<Vec<_>>();
+        let max = rates.iter().flatten().fold(1.0, |acc: f64, p| acc.max(p.1));
+        let magnitude = (max.log10() as usize / 3).min(4);
+        let (div, suffix) = (
+            1000f64.powi(magnitude as i32),
+            ["", "k", "M", "G", "T"][magnitude],
+        );
+        let styles = [
+            ("read", (0, 160, 0)),
+            ("write", (200, 0, 200)),
+            ("storage read", (0, 80, 0)),
+            ("storage write", (100, 0, 100)),
+        ];
+        let rates = rates
+            .into_iter()
+            .zip(styles.iter())
+            .map(|(points, &(label, color))| Curve {
+                label,
+                color,
+                points: points.into_iter().map(|(t, v)| (t, v / div)).collect(),
+            })
+            .collect::<Vec<_>>();
+        panel(
+            &io_area,
+            &format!("I/O ({}B/s)", suffix),
+            time_start..time_end,
+            &rates,
+            &[],
+        )?;
+    }
+
+    let heartbeat_values = |label, ix: usize, color| Curve {
+        label,
+        color,
+        points: hearbeat_series
+            .iter()
+            .map(|r| (time(r), r.values[ix].as_f64()))
+            .collect(),
+    };
+
+    if let Some(sched) = sched {
+        let rate = |label, ix, color| Curve {
+            label,
+            color,
+            points: rate(&hearbeat_series, ix, smooth_cpu),
+        };
+        panel(
+            &sched_area,
+            "Context switches (/s), threads and file descriptors",
+            time_start..time_end,
+            &[
+                rate("voluntary switches", sched[0], (200, 120, 0)),
+                rate("involuntary switches", sched[1], (220, 0, 0)),
+            ],
+            &[
+                heartbeat_values("threads", sched[2], (0, 0, 200)),
+                heartbeat_values("fds", sched[3], (0, 150, 150)),
+            ],
+        )?;
+    }
+
+    if let Some(allocs) = allocs {
+        let rate = |label, ix, color| Curve {
+            label,
+            color,
+            points: rate(&hearbeat_series, ix, smooth_cpu),
+        };
+        panel(
+            &allocs_area,
+            "Allocator calls (/s) and live allocations",
+            time_start..time_end,
+            &[
+                rate("allocations", allocs[0], (0, 0, 200)),
+                rate("deallocations", allocs[1], (0, 150, 150)),
+                rate("reallocations", allocs[2], (200, 120, 0)),
+            ],
+            &[heartbeat_values("live allocations", allocs[3], (0, 0, 0))],
+        )?;
+    }
+
+    if rec.header.column("anon").is_some() {
+        plot_memory(&rec, &format!("{}.memory.png", data), time_start, time_end)?;
+    }
+
+    if rec.header.column("alloc").is_some()
+        && rec.header.columns.iter().any(|c| c.source == "alloc_group")
+    {
+        plot_heap_groups(&rec, &format!("{}.heap.png", data), time_start, time_end)?;
+    }
+
+    if !rec.threads.is_empty() {
+        plot_threads(&rec, &format!("{}.threads.png", data), time_start, time_end)?;
+    }
+
     Ok(())
 }
+
+/// A labelled curve of a chart.
+struct Curve<'a> {
+    label: &'a str,
+    color: (u8, u8, u8),
+    points: Vec<(f32, f64)>,
+}
+
+/// The color of the nth band of a stacked chart.
+fn pick(ix: usize) -> (u8, u8, u8) {
+    Palette99::COLORS[ix % Palette99::COLORS.len()]
+}
+
+/// Draws a panel under the main chart: the `left` curves against the left
+/// axis, the `right` ones against the right axis.
+fn panel(
+    area: &DrawingArea<BitMapBackend, Shift>,
+    caption: &str,
+    time_range: Range<f32>,
+    left: &[Curve],
+    right: &[Curve],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let max = |curves: &[Curve]| {
+        curves
+            .iter()
+            .flat_map(|c| c.points.iter())
+            .fold(1.0, |acc: f64, p| acc.max(p.1))
+    };
+    let mut chart = ChartBuilder::on(area)
+        .x_label_area_size(20)
+        .y_label_area_size(50)
+        .right_y_label_area_size(60)
+        .margin(5)
+        .caption(caption, ("sans-serif", 14).into_font())
+        .build_ranged(time_range.clone(), 0f64..max(left) * 1.1)?
+        .set_secondary_coord(time_range, 0f64..max(right) * 1.1);
+    chart
+        .configure_mesh()
+        .disable_x_mesh()
+        .disable_y_mesh()
+        .y_labels(5)
+        .y_label_formatter(&|&v| format_count(v))
+        .draw()?;
+    if !right.is_empty() {
+        chart
+            .configure_secondary_axes()
+            .y_labels(5)
+            .y_label_formatter(&|&v| format_count(v))
+            .draw()?;
+    }
+    for curve in left {
+        let (r, g, b) = curve.color;
+        chart
+            .draw_series(LineSeries::new(
+                curve.points.iter().cloned(),
+                &RGBColor(r, g, b),
+            ))?
+            .label(curve.label)
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &RGBColor(r, g, b)));
+    }
+    for curve in right {
+        let (r, g, b) = curve.color;
+        chart
+            .draw_secondary_series(LineSeries::new(
+                curve.points.iter().cloned(),
+                &RGBColor(r, g, b),
+            ))?
+            .label(curve.label)
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &RGBColor(r, g, b)));
+    }
+    chart
+        .configure_series_labels()
+        .position(SeriesLabelPosition::UpperLeft)
+        .background_style(&WHITE.mix(0.8))
+        .border_style(&BLACK)
+        .draw()?;
+    Ok(())
+}
+
+/// Draws the `bands` stacked on top of each other, the first one at the
+/// bottom, and the `lines` over them. The bands must share their times.
+/// Values in bytes ("B" unit) are scaled to a binary magnitude.
+fn stacked_chart(
+    png: &str,
+    title: &str,
+    unit: &str,
+    time_range: Range<f32>,
+    mut bands: Vec<Curve>,
+    lines: &[Curve],
+) -> Result<(), Box<dyn std::error::Error>> {
+    for ix in 1..bands.len() {
+        let (below, band) = bands.split_at_mut(ix);
+        for (point, under) in band[0].points.iter_mut().zip(&below[ix - 1].points) {
+            point.1 += under.1;
+        }
+    }
+    let max = bands
+        .last()
+        .into_iter()
+        .chain(lines)
+        .flat_map(|c| c.points.iter())
+        .fold(1.0, |acc: f64, p| acc.max(p.1));
+    let (div, unit) = if unit == "B" {
+        let magnitude = (max.log2() as usize / 10).min(4);
+        (
+            1024f64.powi(magnitude as i32),
+            ["B", "kB", "MB", "GB", "TB"][magnitude],
+        )
+    } else {
+        (1.0, unit)
+    };
+
+    let root = BitMapBackend::new(png, (1024, 768)).into_drawing_area();
+    root.fill(&WHITE)?;
+    let mut chart = ChartBuilder::on(&root)
+        .x_label_area_size(30)
+        .y_label_area_size(60)
+        .margin(5)
+        .caption(
+            format!("{} ({})", title, unit),
+            ("sans-serif", 16).into_font(),
+        )
+        .build_ranged(time_range, 0f64..max * 1.05 / div)?;
+    chart.configure_mesh().disable_x_mesh().draw()?;
+    // draw the top of the stack first, so that each band covers the ones above
+    for band in bands.iter().rev() {
+        let (r, g, b) = band.color;
+        chart
+            .draw_series(AreaSeries::new(
+                band.points.iter().map(|&(t, v)| (t, v / div)),
+                0.0,
+                &RGBColor(r, g, b),
+            ))?
+            .label(band.label)
+            .legend(move |(x, y)| {
+                Rectangle::new([(x, y - 4), (x + 20, y + 4)], RGBColor(r, g, b).filled())
+            });
+    }
+    for line in lines {
+        let (r, g, b) = line.color;
+        chart
+            .draw_series(LineSeries::new(
+                line.points.iter().map(|&(t, v)| (t, v / div)),
+                &RGBColor(r, g, b),
+            ))?
+            .label(line.label)
+            .legend(move |(x, y)| PathElement::new(vec![(x, y), (x + 20, y)], &RGBColor(r, g, b)));
+    }
+    chart
+        .configure_series_labels()
+        .position(SeriesLabelPosition::UpperLeft)
+        .background_style(&WHITE.mix(0.8))
+        .border_style(&BLACK)
+        .draw()?;
+    Ok(())
+}
+
+/// Draws the composition of the resident memory (anonymous, file-backed and
+/// shared, stacked) plus swap, and the PSS and locked memory as lines.
+fn plot_memory(
+    rec: &Recording,
+    png: &str,
+    time_start: f32,
+    time_end: f32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rows = rec
+        .rows
+        .iter()
+        .filter(|r| in_time_range(r, time_start, time_end))
+        .collect::<Vec<_>>();
+    let curve = |label, name, color| -> Result<Curve, Box<dyn std::error::Error>> {
+        let col = column(rec, name)?;
+        Ok(Curve {
+            label,
+            color,
+            points: rows
+                .iter()
+                .map(|row| (time(row), row.values[col].as_f64()))
+                .collect(),
+        })
+    };
+    let bands = vec![
+        curve("anonymous", "anon", (70, 110, 200))?,
+        curve("file-backed", "file", (240, 170, 60))?,
+        curve("shared", "shmem", (120, 190, 110))?,
+        curve("swap", "swap", (200, 80, 80))?,
+    ];
+    let lines = [
+        curve("pss", "pss", (0, 0, 0))?,
+        curve("locked", "locked", (150, 0, 150))?,
+    ];
+    stacked_chart(png, "Memory", "B", time_start..time_end, bands, &lines)
+}
+
+/// Draws the live heap of the thread groups, stacked, under the whole live heap
+/// of the process. What is not attributed to a group is drawn as "other".
+fn plot_heap_groups(
+    rec: &Recording,
+    png: &str,
+    time_start: f32,
+    time_end: f32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rows = rec
+        .rows
+        .iter()
+        .filter(|r| in_time_range(r, time_start, time_end))
+        .collect::<Vec<_>>();
+    let (alloc, free) = (column(rec, "alloc")?, column(rec, "free")?);
+    let mut other = rows
+        .iter()
+        .map(|r| (time(r), r.values[alloc].as_f64() - r.values[free].as_f64()))
+        .collect::<Vec<_>>();
+    let mut bands = vec![];
+    for (col, c) in rec.header.columns.iter().enumerate() {
+        if c.source != "alloc_group" {
+            continue;
+        }
+        // a group freeing memory allocated by another can go negative
+        let points = rows
+            .iter()
+            .map(|row| (time(row), row.values[col].as_f64().max(0.0)))
+            .collect::<Vec<_>>();
+        for (other, point) in other.iter_mut().zip(&points) {
+            other.1 -= point.1;
+        }
+        bands.push(Curve {
+            label: c.name.trim_start_matches("heap."),
+            color: pick(bands.len()),
+            points,
+        });
+    }
+    for point in &mut other {
+        point.1 = point.1.max(0.0);
+    }
+    bands.push(Curve {
+        label: "other",
+        color: (200, 200, 200),
+        points: other,
+    });
+    stacked_chart(
+        png,
+        "Live heap by thread group",
+        "B",
+        time_start..time_end,
+        bands,
+        &[],
+    )
+}
+
+/// CPU usage of a thread, in cores, over each interval between two samples.
+struct ThreadUsage {
+    label: String,
+    rates: Vec<f32>,
+    total: f32,
+}
+
+/// Draws the CPU usage of the threads, stacked. The busiest threads get their
+/// own band, the others are summed up.
+fn plot_threads(
+    rec: &Recording,
+    png: &str,
+    time_start: f32,
+    time_end: f32,
+) -> Result<(), Box<dyn std::error::Error>> {
+    const BANDS: usize = 10;
+    let samples = rec
+        .threads
+        .iter()
+        .filter(|s| time_start <= s.time as f32 && s.time as f32 <= time_end)
+        .collect::<Vec<_>>();
+    if samples.len() < 2 {
+        return Ok(());
+    }
+    // CPU times have a clock tick granularity: smooth over 0.2s, as for the
+    // process CPU
+    let interval = (samples[samples.len() - 1].time - samples[0].time) / samples.len() as f64;
+    let smooth = ((0.2 / interval) as usize).clamp(1, samples.len() - 1);
+    let times = samples[smooth..]
+        .iter()
+        .map(|s| s.time as f32)
+        .collect::<Vec<_>>();
+    let mut usages: Vec<ThreadUsage> = vec![];
+    let mut by_tid = HashMap::new();
+    for (ix, (before, after)) in samples.iter().zip(&samples[smooth..]).enumerate() {
+        let dt = (after.time - before.time) as f32;
+        for thread in &after.threads {
+            let cpu = |t: &format::ThreadCpu| (t.user_time + t.system_time) as f32;
+            let previous = before
+                .threads
+                .iter()
+                .find(|t| t.tid == thread.tid)
+                .map(cpu)
+                .unwrap_or(0.0);
+            let rate = ((cpu(thread) - previous) / dt).max(0.0);
+            let usage = *by_tid.entry(thread.tid).or_insert_with(|| {
+                usages.push(ThreadUsage {
+                    label: format!("{} ({})", thread.name, thread.tid),
+                    rates: vec![0.0; times.len()],
+                    total: 0.0,
+                });
+                usages.len() - 1
+            });
+            usages[usage].rates[ix] = rate;
+            usages[usage].total += rate;
+        }
+    }
+    usages.sort_by(|a, b| b.total.partial_cmp(&a.total).unwrap());
+    if usages.len() > BANDS {
+        let mut others = ThreadUsage {
+            label: format!("{} other threads", usages.len() - BANDS + 1),
+            rates: vec![0.0; times.len()],
+            total: 0.0,
+        };
+        for usage in usages.drain(BANDS - 1..) {
+            for (acc, rate) in others.rates.iter_mut().zip(usage.rates) {
+                *acc += rate;
+            }
+            others.total += usage.total;
+        }
+        usages.push(others);
+    }
+    let bands = usages
+        .iter()
+        .enumerate()
+        .map(|(ix, usage)| Curve {
+            label: &usage.label,
+            color: pick(ix),
+            points: times
+                .iter()
+                .cloned()
+                .zip(usage.rates.iter().map(|&rate| rate as f64))
+                .collect(),
+        })
+        .collect();
+    stacked_chart(
+        png,
+        "CPU usage per thread",
+        "cores",
+        time_start..time_end,
+        bands,
+        &[],
+    )
+}
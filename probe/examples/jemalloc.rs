@@ -7,10 +7,11 @@ use jemallocator::Jemalloc;
 readings_probe::wrap_global_allocator!(Jemalloc);
 
 fn main() -> readings_probe::ReadingsResult<()> {
-    let mut probe =
-        readings_probe::Probe::new(std::fs::File::create("readings.out").unwrap()).unwrap();
-    let progress = probe.register_i64("done".to_string())?;
-    probe.spawn_heartbeat(Duration::from_millis(1000))?;
+    let mut builder =
+        readings_probe::ProbeBuilder::new(std::fs::File::create("readings.out").unwrap())
+            .heartbeat(Duration::from_millis(1000));
+    let progress = builder.register_i64("done");
+    let probe = builder.start()?;
     let mut vec = vec![];
     for i in 0..5 {
         std::thread::sleep(Duration::from_millis(3000));
@@ -1,7 +1,318 @@
-use std::sync::atomic::AtomicUsize;
+use std::cell::Cell;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicIsize, AtomicUsize};
+use std::sync::Mutex;
 
 pub static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
 pub static FREEED: AtomicUsize = AtomicUsize::new(0);
+/// Number of `alloc` calls
+pub static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// Number of `dealloc` calls
+pub static DEALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// Number of `realloc` calls, not counted in allocations and deallocations
+pub static REALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+/// Highest live heap in bytes since the last `reset_peak`
+pub static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// Maximum number of thread groups.
+pub const MAX_GROUPS: usize = 16;
+
+const NO_GROUP: usize = usize::MAX;
+
+/// Bytes allocated minus bytes freed by the threads of each group.
+static GROUP_LIVE: [AtomicIsize; MAX_GROUPS] = {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const ZERO: AtomicIsize = AtomicIsize::new(0);
+    [ZERO; MAX_GROUPS]
+};
+/// Group names, which are also the thread name prefixes of their members.
+static GROUPS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+/// Bumped at each new group, for the threads to look for theirs again.
+static GROUPS_VERSION: AtomicUsize = AtomicUsize::new(0);
+
+/// Bytes currently allocated through the instrumented allocator.
+pub fn live() -> usize {
+    ALLOCATED.load(Relaxed).saturating_sub(FREEED.load(Relaxed))
+}
+
+/// Returns the highest live heap since the previous call, and starts a new
+/// interval from the current live heap.
+///
+/// The probe calls it for each line it writes, to record the peak in the
+/// `heap_peak` column: calling it from elsewhere shortens the probe intervals.
+pub fn reset_peak() -> usize {
+    let live = live();
+    PEAK.swap(live, Relaxed).max(live)
+}
+
+/// Allocations attributed to a closure by `measure`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Bytes allocated, reallocations included
+    pub allocated: usize,
+    /// Bytes freed, reallocations included
+    pub freed: usize,
+    /// Number of `alloc` calls
+    pub allocations: usize,
+    /// Number of `dealloc` calls
+    pub deallocations: usize,
+    /// Number of `realloc` calls
+    pub reallocations: usize,
+    /// Highest live heap reached, in bytes above the heap at the start
+    pub peak: usize,
+}
+
+/// Counters of a single thread.
+struct ThreadCounters {
+    allocated: Cell<usize>,
+    freed: Cell<usize>,
+    allocations: Cell<usize>,
+    deallocations: Cell<usize>,
+    reallocations: Cell<usize>,
+    /// Bytes allocated minus bytes freed by the thread. It goes negative when
+    /// the thread frees memory allocated by another one.
+    live: Cell<isize>,
+    peak: Cell<isize>,
+    /// Index of the thread group, or NO_GROUP
+    group: Cell<usize>,
+    /// GROUPS_VERSION when the group was looked up
+    group_version: Cell<usize>,
+    /// Set by `join_group`: the thread name is not looked at anymore
+    joined: Cell<bool>,
+    /// Guards against allocations made while looking up the group
+    resolving: Cell<bool>,
+}
+
+impl ThreadCounters {
+    /// The counters since the thread started, peak excepted.
+    fn stats(&self) -> AllocStats {
+        AllocStats {
+            allocated: self.allocated.get(),
+            freed: self.freed.get(),
+            allocations: self.allocations.get(),
+            deallocations: self.deallocations.get(),
+            reallocations: self.reallocations.get(),
+            peak: 0,
+        }
+    }
+
+    fn grow(&self, size: usize) {
+        let live = self.live.get().wrapping_add(size as isize);
+        self.live.set(live);
+        if live > self.peak.get() {
+            self.peak.set(live);
+        }
+    }
+
+    fn shrink(&self, size: usize) {
+        self.live.set(self.live.get().wrapping_sub(size as isize));
+    }
+
+    /// The group of the thread, looked up from its name if groups were
+    /// registered since the last lookup.
+    ///
+    /// Only called on allocations, and skipped once the thread is torn down:
+    /// `thread::current()` may panic then, which aborts in the allocator.
+    fn resolve_group(&self) -> usize {
+        let version = GROUPS_VERSION.load(Relaxed);
+        if version != self.group_version.get() && !self.joined.get() && !self.resolving.get() {
+            self.resolving.set(true);
+            // a thread registering a group may be allocating with the lock held
+            let groups = match ALIVE.try_with(|_| ()) {
+                Ok(()) => GROUPS.try_lock().ok(),
+                Err(_) => None,
+            };
+            if let Some(groups) = groups {
+                let thread = std::thread::current();
+                let group = thread
+                    .name()
+                    .and_then(|name| groups.iter().position(|g| name.starts_with(g.as_str())));
+                self.group.set(group.unwrap_or(NO_GROUP));
+                self.group_version.set(version);
+            }
+            self.resolving.set(false);
+        }
+        self.group.get()
+    }
+}
+
+fn add_to_group(group: usize, size: isize) {
+    if let Some(live) = GROUP_LIVE.get(group) {
+        live.fetch_add(size, Relaxed);
+    }
+}
+
+/// Registers a thread group and returns its index. Registering a name twice
+/// returns the same group.
+///
+/// Returns None once MAX_GROUPS groups are registered.
+pub(crate) fn register_group(name: &str) -> Option<usize> {
+    let mut groups = GROUPS.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(ix) = groups.iter().position(|g| g == name) {
+        return Some(ix);
+    }
+    if groups.len() == MAX_GROUPS {
+        return None;
+    }
+    groups.push(name.to_string());
+    GROUPS_VERSION.fetch_add(1, Relaxed);
+    Some(groups.len() - 1)
+}
+
+/// Bytes allocated minus bytes freed by the threads of a group.
+pub(crate) fn group_live(group: usize) -> isize {
+    GROUP_LIVE[group].load(Relaxed)
+}
+
+/// Makes the current thread a member of the named thread group, whatever its
+/// name, from now on.
+///
+/// Groups are recorded by the probe when registered on the builder with
+/// `ProbeBuilder::alloc_group`. Returns false if the group can not be
+/// registered because MAX_GROUPS groups already are.
+pub fn join_group(name: &str) -> bool {
+    match register_group(name) {
+        Some(group) => {
+            let _ = THREAD.try_with(|t| {
+                t.group.set(group);
+                t.joined.set(true);
+            });
+            true
+        }
+        None => false,
+    }
+}
+
+/// Set for the lifetime of a thread, until its thread locals are destroyed.
+struct Alive;
+
+impl Drop for Alive {
+    fn drop(&mut self) {}
+}
+
+thread_local! {
+    // no destructor and a const initializer: usable from the allocator,
+    // including while the thread is torn down
+    static THREAD: ThreadCounters = const {
+        ThreadCounters {
+            allocated: Cell::new(0),
+            freed: Cell::new(0),
+            allocations: Cell::new(0),
+            deallocations: Cell::new(0),
+            reallocations: Cell::new(0),
+            live: Cell::new(0),
+            peak: Cell::new(0),
+            group: Cell::new(NO_GROUP),
+            group_version: Cell::new(0),
+            joined: Cell::new(false),
+            resolving: Cell::new(false),
+        }
+    };
+    // initialized on the first group lookup, which is guarded against the
+    // allocations made while registering its destructor
+    static ALIVE: Alive = const { Alive };
+}
+
+/// Runs `f` and returns its result with the allocations made by the current
+/// thread while it ran.
+///
+/// Other threads allocating in the meantime are not accounted for, nor are
+/// the threads `f` spawns. Measures can be nested. All counts are zero unless
+/// the global allocator is instrumented with `instrumented_allocator!` or
+/// `wrap_global_allocator!`.
+///
+/// ```rust
+/// readings_probe::instrumented_allocator!();
+///
+/// fn main() {
+///     let (v, stats) = readings_probe::alloc::measure(|| vec![0u8; 1024]);
+///     assert_eq!(stats.allocated, 1024);
+///     assert_eq!(stats.allocations, 1);
+///     assert_eq!(stats.peak, 1024);
+///     drop(v);
+/// }
+/// ```
+pub fn measure<R, F: FnOnce() -> R>(f: F) -> (R, AllocStats) {
+    let (before, live, outer_peak) = THREAD.with(|t| {
+        let outer_peak = t.peak.replace(t.live.get());
+        (t.stats(), t.live.get(), outer_peak)
+    });
+    let result = f();
+    let stats = THREAD.with(|t| {
+        let peak = t.peak.get();
+        t.peak.set(outer_peak.max(peak));
+        let after = t.stats();
+        AllocStats {
+            allocated: after.allocated.wrapping_sub(before.allocated),
+            freed: after.freed.wrapping_sub(before.freed),
+            allocations: after.allocations.wrapping_sub(before.allocations),
+            deallocations: after.deallocations.wrapping_sub(before.deallocations),
+            reallocations: after.reallocations.wrapping_sub(before.reallocations),
+            peak: (peak - live).max(0) as usize,
+        }
+    });
+    (result, stats)
+}
+
+fn update_peak() {
+    let live = live();
+    // most allocations do not make a new peak: avoid the contended write
+    if live > PEAK.load(Relaxed) {
+        PEAK.fetch_max(live, Relaxed);
+    }
+}
+
+/// Called by the instrumented allocator after a successful allocation.
+#[doc(hidden)]
+#[inline]
+pub fn count_alloc(size: usize) {
+    ALLOCATED.fetch_add(size, Relaxed);
+    ALLOCATIONS.fetch_add(1, Relaxed);
+    update_peak();
+    let _ = THREAD.try_with(|t| {
+        t.allocated.set(t.allocated.get().wrapping_add(size));
+        t.allocations.set(t.allocations.get().wrapping_add(1));
+        t.grow(size);
+        add_to_group(t.resolve_group(), size as isize);
+    });
+}
+
+/// Called by the instrumented allocator before a deallocation.
+#[doc(hidden)]
+#[inline]
+pub fn count_dealloc(size: usize) {
+    FREEED.fetch_add(size, Relaxed);
+    DEALLOCATIONS.fetch_add(1, Relaxed);
+    let _ = THREAD.try_with(|t| {
+        t.freed.set(t.freed.get().wrapping_add(size));
+        t.deallocations.set(t.deallocations.get().wrapping_add(1));
+        t.shrink(size);
+        add_to_group(t.group.get(), -(size as isize));
+    });
+}
+
+/// Called by the instrumented allocator after a successful reallocation.
+#[doc(hidden)]
+#[inline]
+pub fn count_realloc(old_size: usize, new_size: usize) {
+    FREEED.fetch_add(old_size, Relaxed);
+    ALLOCATED.fetch_add(new_size, Relaxed);
+    REALLOCATIONS.fetch_add(1, Relaxed);
+    if new_size > old_size {
+        update_peak();
+    }
+    let _ = THREAD.try_with(|t| {
+        t.freed.set(t.freed.get().wrapping_add(old_size));
+        t.allocated.set(t.allocated.get().wrapping_add(new_size));
+        t.reallocations.set(t.reallocations.get().wrapping_add(1));
+        t.shrink(old_size);
+        t.grow(new_size);
+        add_to_group(
+            t.resolve_group(),
+            (new_size as isize).wrapping_sub(old_size as isize),
+        );
+    });
+}
 
 /// Setup global allocator instrumentation, to track rust-managed memory.
 ///
@@ -21,30 +332,32 @@ macro_rules! wrap_global_allocator {
 
         unsafe impl std::alloc::GlobalAlloc for InstrumentedAllocator {
             unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
-                use std::sync::atomic::Ordering::Relaxed;
                 let ptr = $alloc.alloc(layout);
                 if !ptr.is_null() {
-                    $crate::alloc::ALLOCATED.fetch_add(layout.size(), Relaxed);
+                    $crate::alloc::count_alloc(layout.size());
+                }
+                ptr
+            }
+            unsafe fn alloc_zeroed(&self, layout: std::alloc::Layout) -> *mut u8 {
+                let ptr = $alloc.alloc_zeroed(layout);
+                if !ptr.is_null() {
+                    $crate::alloc::count_alloc(layout.size());
                 }
                 ptr
             }
             unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
-                use std::sync::atomic::Ordering::Relaxed;
                 if !ptr.is_null() {
-                    $crate::alloc::FREEED.fetch_add(layout.size(), Relaxed);
+                    $crate::alloc::count_dealloc(layout.size());
                 }
                 $alloc.dealloc(ptr, layout);
             }
             unsafe fn realloc(&self, ptr: *mut u8, layout: std::alloc::Layout, new_size: usize) -> *mut u8 {
-                use std::sync::atomic::Ordering::*;
-                if !ptr.is_null() {
-                    $crate::alloc::FREEED.fetch_add(layout.size(), Relaxed);
+                let new_ptr = $alloc.realloc(ptr, layout, new_size);
+                // on failure, the original block is left untouched
+                if !new_ptr.is_null() {
+                    $crate::alloc::count_realloc(layout.size(), new_size);
                 }
-                let ptr = $alloc.realloc(ptr, layout, new_size);
-                if !ptr.is_null() {
-                    $crate::alloc::ALLOCATED.fetch_add(layout.size(), Relaxed);
-                }
-                ptr
+                new_ptr
             }
         }
     };
@@ -0,0 +1,303 @@
+use std::io;
+use std::sync::atomic::{AtomicI64, AtomicU64};
+use std::sync::Arc;
+use std::time;
+
+use crate::format::{Encoder, Encoding};
+use crate::metric::Registered;
+use crate::source::AllocGroupsSource;
+use crate::{
+    AtomicF64, Builtin, GaugeFn, Histogram, Metric, Probe, ProbeData, ReadingSource,
+    ReadingsResult, DEFAULT_BUFFER,
+};
+
+/// Configures a probe before it starts recording.
+///
+/// All user-defined metrics must be registered on the builder: once `build()`
+/// or `start()` has been called, the metric set is frozen and the resulting
+/// `Probe` can only log events.
+///
+/// ```rust
+/// fn main() -> readings_probe::ReadingsResult<()> {
+///     let mut builder = readings_probe::ProbeBuilder::new(std::io::sink())
+///         .heartbeat(std::time::Duration::from_millis(100))
+///         .metadata("program", "example");
+///     let progress = builder.register_i64("progress");
+///     let probe = builder.start()?;
+///     progress.store(42, std::sync::atomic::Ordering::Relaxed);
+///     probe.log_event("done")?;
+///     Ok(())
+/// }
+/// ```
+pub struct ProbeBuilder {
+    writer: Box<dyn io::Write + Send>,
+    heartbeat: Option<time::Duration>,
+    encoding: Encoding,
+    buffer: usize,
+    thread_cpu: bool,
+    builtins: Vec<Builtin>,
+    sources: Vec<Box<dyn ReadingSource>>,
+    alloc_groups: Vec<String>,
+    metadata: Vec<(String, String)>,
+    metrics: Vec<Registered>,
+}
+
+impl ProbeBuilder {
+    /// Creates a builder for a probe logging its data to Write implementation
+    /// (usually a file).
+    pub fn new<W: io::Write + Send + 'static>(write: W) -> ProbeBuilder {
+        ProbeBuilder {
+            writer: Box::new(write),
+            heartbeat: None,
+            encoding: Encoding::Text,
+            buffer: DEFAULT_BUFFER,
+            thread_cpu: false,
+            builtins: Builtin::ALL
+                .iter()
+                .cloned()
+                .filter(|b| b.enabled_by_default())
+                .collect(),
+            sources: vec![],
+            alloc_groups: vec![],
+            metadata: vec![],
+            metrics: vec![],
+        }
+    }
+
+    /// Sets the interval of the heartbeat spawned by `start()`.
+    pub fn heartbeat(mut self, interval: time::Duration) -> ProbeBuilder {
+        self.heartbeat = Some(interval);
+        self
+    }
+
+    /// Sets the output encoding. Defaults to text.
+    ///
+    /// The binary encoding is much more compact and cheaper to produce, which
+    /// matters with short heartbeats or long runs. `readings convert` turns it
+    /// back into text.
+    pub fn encoding(mut self, encoding: Encoding) -> ProbeBuilder {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets how many samples can be queued for the writer thread. Defaults
+    /// to 1024.
+    ///
+    /// Samples logged while the queue is full are dropped, and their count
+    /// is written to the output.
+    pub fn buffer(mut self, samples: usize) -> ProbeBuilder {
+        self.buffer = samples;
+        self
+    }
+
+    /// Records the CPU times of every thread at each heartbeat. Defaults to
+    /// false.
+    ///
+    /// Only supported on Linux and Android, where it walks `/proc/self/task`.
+    pub fn thread_cpu(mut self, enabled: bool) -> ProbeBuilder {
+        self.thread_cpu = enabled;
+        self
+    }
+
+    /// Records the resident memory composition (anonymous, file-backed,
+    /// shared), PSS, swapped and locked memory in every line. Defaults to
+    /// false.
+    ///
+    /// Only supported on Linux and Android. Computing the PSS walks all the
+    /// memory mappings of the process, which gets expensive with many of
+    /// them.
+    pub fn memory_breakdown(self, enabled: bool) -> ProbeBuilder {
+        self.builtin(Builtin::MemoryBreakdown, enabled)
+    }
+
+    /// Records the pressure stall information of the CPU, memory and I/O in
+    /// every line: how long some or all tasks were stalled waiting for them.
+    /// Defaults to false.
+    ///
+    /// Only supported on Linux 4.20 and later, with pressure tracking enabled.
+    /// The pressure of the process cgroup is preferred to the system-wide one.
+    pub fn pressure(self, enabled: bool) -> ProbeBuilder {
+        self.builtin(Builtin::Pressure, enabled)
+    }
+
+    /// Enables or disables one of the built-in readings.
+    ///
+    /// Readings that are not available on the system are skipped, even if
+    /// enabled.
+    pub fn builtin(mut self, builtin: Builtin, enabled: bool) -> ProbeBuilder {
+        self.builtins.retain(|&b| b != builtin);
+        if enabled {
+            self.builtins.push(builtin);
+        }
+        self
+    }
+
+    /// Registers a custom source, sampled in every line after the built-in
+    /// readings.
+    pub fn source<S: ReadingSource + 'static>(mut self, source: S) -> ProbeBuilder {
+        self.sources.push(Box::new(source));
+        self
+    }
+
+    /// Records the live heap of a group of threads in every line: the bytes
+    /// allocated minus the bytes freed by the threads whose name starts with
+    /// `name`, or that joined the group with `alloc::join_group(name)`.
+    ///
+    /// Needs the instrumented allocator. Memory freed by another thread than
+    /// the one that allocated it is accounted for in the group of the
+    /// freeing thread, which may then go negative. At most
+    /// `alloc::MAX_GROUPS` groups can be registered.
+    ///
+    /// ```rust
+    /// readings_probe::instrumented_allocator!();
+    ///
+    /// fn main() -> readings_probe::ReadingsResult<()> {
+    ///     let probe = readings_probe::ProbeBuilder::new(std::io::sink())
+    ///         .alloc_group("worker")
+    ///         .start()?;
+    ///     std::thread::Builder::new()
+    ///         .name("worker-1".to_string())
+    ///         .spawn(|| vec![0u8; 1 << 20].len())?
+    ///         .join()
+    ///         .unwrap();
+    ///     probe.log_event("done")?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn alloc_group<S: AsRef<str>>(mut self, name: S) -> ProbeBuilder {
+        self.alloc_groups.push(name.as_ref().to_string());
+        self
+    }
+
+    /// Adds a key/value pair to the file header metadata block.
+    pub fn metadata<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> ProbeBuilder {
+        self.metadata
+            .push((key.as_ref().to_string(), value.as_ref().to_string()));
+        self
+    }
+
+    /// Register an i64 used-defined metric.
+    ///
+    /// The result is shared AtomicI64 that can be used by client code to share
+    /// communicate updates with the probe.
+    pub fn register_i64<S: AsRef<str>>(&mut self, name: S) -> Arc<AtomicI64> {
+        let it = Arc::new(AtomicI64::new(0));
+        self.register(name, Metric::I64(it.clone()));
+        it
+    }
+
+    /// Register an u64 used-defined metric.
+    pub fn register_u64<S: AsRef<str>>(&mut self, name: S) -> Arc<AtomicU64> {
+        let it = Arc::new(AtomicU64::new(0));
+        self.register(name, Metric::U64(it.clone()));
+        it
+    }
+
+    /// Register an f64 used-defined metric.
+    pub fn register_f64<S: AsRef<str>>(&mut self, name: S) -> Arc<AtomicF64> {
+        let it = Arc::new(AtomicF64::new(0.0));
+        self.register(name, Metric::F64(it.clone()));
+        it
+    }
+
+    /// Register a monotonic counter used-defined metric.
+    ///
+    /// Counters are meant to be incremented (with `fetch_add`) and are plotted
+    /// as a per-second rate.
+    pub fn register_counter<S: AsRef<str>>(&mut self, name: S) -> Arc<AtomicU64> {
+        let it = Arc::new(AtomicU64::new(0));
+        self.register(name, Metric::Counter(it.clone()));
+        it
+    }
+
+    /// Register a latency histogram.
+    ///
+    /// At each line, the probe writes the p50, p90, p99 and max (in
+    /// nanoseconds) of the durations recorded since the previous line.
+    pub fn register_histogram<S: AsRef<str>>(&mut self, name: S) -> Arc<Histogram> {
+        let it = Arc::new(Histogram::new());
+        self.register(name, Metric::Histogram(it.clone()));
+        it
+    }
+
+    /// Register a gauge computed by a callback.
+    ///
+    /// The callback is called from the probe each time a line is written,
+    /// heartbeats included, and must not log on the probe itself. If it
+    /// panics, the line records a zero and an "error" field naming the metric.
+    ///
+    /// ```rust
+    /// use std::collections::HashMap;
+    /// use std::sync::{Arc, Mutex};
+    ///
+    /// fn main() -> readings_probe::ReadingsResult<()> {
+    ///     let cache: Arc<Mutex<HashMap<String, String>>> = Default::default();
+    ///     let mut builder = readings_probe::ProbeBuilder::new(std::io::sink());
+    ///     let entries = cache.clone();
+    ///     builder.register_gauge_fn("cache_entries", move || entries.lock().unwrap().len() as i64);
+    ///     let probe = builder.build()?;
+    ///     cache.lock().unwrap().insert("key".into(), "value".into());
+    ///     probe.log_event("cached")?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn register_gauge_fn<S, F>(&mut self, name: S, f: F)
+    where
+        S: AsRef<str>,
+        F: Fn() -> i64 + Send + Sync + 'static,
+    {
+        self.register(name, Metric::GaugeFn(GaugeFn::new(f)));
+    }
+
+    /// Sets the unit of a registered metric, as written in the file header.
+    ///
+    /// Histograms are always recorded in nanoseconds.
+    pub fn set_unit<S: AsRef<str>, U: AsRef<str>>(&mut self, name: S, unit: U) {
+        if let Some(m) = self.metrics.iter_mut().find(|m| m.name == name.as_ref()) {
+            m.unit = unit.as_ref().to_string();
+        }
+    }
+
+    fn register<S: AsRef<str>>(&mut self, name: S, metric: Metric) {
+        self.metrics.push(Registered::new(name.as_ref(), metric));
+    }
+
+    /// Writes the header and returns the probe, without spawning a heartbeat.
+    pub fn build(self) -> ReadingsResult<Probe> {
+        // built-in readings come first, in a stable order
+        let mut sources: Vec<Box<dyn ReadingSource>> = Builtin::ALL
+            .iter()
+            .filter(|b| self.builtins.contains(b))
+            .filter_map(|b| b.source())
+            .collect();
+        if !self.alloc_groups.is_empty() {
+            sources.push(Box::new(AllocGroupsSource::new(&self.alloc_groups)?));
+        }
+        sources.extend(self.sources);
+        let mut data = ProbeData::new(
+            self.writer,
+            Encoder::new(self.encoding),
+            self.buffer,
+            sources,
+        );
+        if let Some(interval) = self.heartbeat {
+            data.metadata
+                .push(("heartbeat".to_string(), interval.as_secs_f64().to_string()));
+        }
+        data.metadata.extend(self.metadata);
+        data.metrics = self.metrics;
+        data.thread_cpu = self.thread_cpu;
+        data.start();
+        Ok(Probe::from_data(data))
+    }
+
+    /// Builds the probe and spawns its heartbeat, if an interval was set.
+    pub fn start(self) -> ReadingsResult<Probe> {
+        let heartbeat = self.heartbeat;
+        let mut probe = self.build()?;
+        if let Some(interval) = heartbeat {
+            probe.spawn_heartbeat(interval)?;
+        }
+        Ok(probe)
+    }
+}
@@ -0,0 +1,815 @@
+//! Readings file format.
+//!
+//! A readings file starts with a magic line (`#ReadingsV1` or `#ReadingsV2`).
+//!
+//! V2 files are self-describing. The header is made of `#meta <key> <value>`
+//! lines (program name, pid, start time, ...) and of one `#column <name>
+//! <type> <unit> <kind> <source>` line per column. Each following line is a
+//! row: one whitespace separated value per column, optionally followed by an
+//! event label and its `<key>:<type>=<value>` fields. Span rows carry a
+//! `begin=<id>[/<parent>]` or `end=<id>` token right after the label. Names,
+//! labels, field keys and values, and metadata values are escaped so they
+//! never contain whitespace. A `#dropped <count>` line records samples the probe
+//! had to discard because its writer was lagging behind. Per-thread CPU
+//! times are written as `#threads <time> <tid>:<utime>:<stime>:<name>...`
+//! lines.
+//!
+//! Rows can alternatively be written in a compact binary encoding (see
+//! `binary`), selected with `Encoding`. `read` detects it automatically.
+//!
+//! V1 files have a fixed set of OS and allocator columns, followed by
+//! user-defined i64 gauges, and are only supported for reading.
+use std::fmt;
+use std::io;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use crate::{ReadingsError, ReadingsResult};
+
+pub mod binary;
+
+pub const V1_MAGIC: &str = "#ReadingsV1";
+pub const V2_MAGIC: &str = "#ReadingsV2";
+
+/// Type of the values in a column.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ValueType {
+    I64,
+    U64,
+    F64,
+}
+
+impl ValueType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ValueType::I64 => "i64",
+            ValueType::U64 => "u64",
+            ValueType::F64 => "f64",
+        }
+    }
+
+    /// Parses a value of this type.
+    pub fn parse(&self, s: &str) -> ReadingsResult<Value> {
+        fn parse<T: FromStr>(s: &str) -> ReadingsResult<T> {
+            s.parse()
+                .map_err(|_| ReadingsError::Format(format!("Can not parse value: {}", s)))
+        }
+        Ok(match self {
+            ValueType::I64 => Value::I64(parse(s)?),
+            ValueType::U64 => Value::U64(parse(s)?),
+            ValueType::F64 => Value::F64(parse(s)?),
+        })
+    }
+}
+
+impl FromStr for ValueType {
+    type Err = ReadingsError;
+    fn from_str(s: &str) -> ReadingsResult<ValueType> {
+        match s {
+            "i64" => Ok(ValueType::I64),
+            "u64" => Ok(ValueType::U64),
+            "f64" => Ok(ValueType::F64),
+            _ => Err(ReadingsError::Format(format!("Unknown type: {}", s))),
+        }
+    }
+}
+
+/// How the values of a column evolve, and how they should be plotted.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    /// A value sampled at each line.
+    Gauge,
+    /// A monotonically increasing value, plotted as a rate.
+    Counter,
+    /// One percentile of a histogram, the column name being suffixed by
+    /// `.p50`, `.p90`, `.p99` or `.max`.
+    Histogram,
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Kind::Gauge => "gauge",
+            Kind::Counter => "counter",
+            Kind::Histogram => "histogram",
+        }
+    }
+}
+
+impl FromStr for Kind {
+    type Err = ReadingsError;
+    fn from_str(s: &str) -> ReadingsResult<Kind> {
+        match s {
+            "gauge" => Ok(Kind::Gauge),
+            "counter" => Ok(Kind::Counter),
+            "histogram" => Ok(Kind::Histogram),
+            _ => Err(ReadingsError::Format(format!("Unknown kind: {}", s))),
+        }
+    }
+}
+
+/// Column descriptor.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub ty: ValueType,
+    /// Unit of the values, "-" if none.
+    pub unit: String,
+    pub kind: Kind,
+    /// Group the column belongs to: "probe", "os", "alloc" or "user".
+    pub source: String,
+}
+
+impl Column {
+    pub fn new(name: &str, ty: ValueType, unit: &str, kind: Kind, source: &str) -> Column {
+        Column {
+            name: name.to_string(),
+            ty,
+            unit: unit.to_string(),
+            kind,
+            source: source.to_string(),
+        }
+    }
+}
+
+/// A value in a row.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+}
+
+impl Value {
+    pub fn as_f64(&self) -> f64 {
+        match *self {
+            Value::I64(v) => v as f64,
+            Value::U64(v) => v as f64,
+            Value::F64(v) => v,
+        }
+    }
+}
+
+/// A typed value attached to an event.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldValue {
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Bool(bool),
+    Str(String),
+}
+
+impl FieldValue {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            FieldValue::I64(_) => "i64",
+            FieldValue::U64(_) => "u64",
+            FieldValue::F64(_) => "f64",
+            FieldValue::Bool(_) => "bool",
+            FieldValue::Str(_) => "str",
+        }
+    }
+
+    /// Parses a value from its type name and its unescaped text form.
+    pub fn parse(ty: &str, s: &str) -> ReadingsResult<FieldValue> {
+        let err = || ReadingsError::Format(format!("Can not parse {} as {}", s, ty));
+        Ok(match ty {
+            "i64" => FieldValue::I64(s.parse().map_err(|_| err())?),
+            "u64" => FieldValue::U64(s.parse().map_err(|_| err())?),
+            "f64" => FieldValue::F64(s.parse().map_err(|_| err())?),
+            "bool" => FieldValue::Bool(s.parse().map_err(|_| err())?),
+            "str" => FieldValue::Str(s.to_string()),
+            _ => return Err(err()),
+        })
+    }
+}
+
+impl fmt::Display for FieldValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FieldValue::I64(v) => write!(f, "{}", v),
+            FieldValue::U64(v) => write!(f, "{}", v),
+            FieldValue::F64(v) => write!(f, "{}", v),
+            FieldValue::Bool(v) => write!(f, "{}", v),
+            FieldValue::Str(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+macro_rules! field_from {
+    ($($t:ty => $variant:ident as $as:ty),*) => {
+        $(impl From<$t> for FieldValue {
+            fn from(v: $t) -> FieldValue {
+                FieldValue::$variant(v as $as)
+            }
+        })*
+    };
+}
+
+field_from!(i64 => I64 as i64, i32 => I64 as i64, u64 => U64 as u64, u32 => U64 as u64,
+    usize => U64 as u64, f64 => F64 as f64, f32 => F64 as f64);
+
+impl From<bool> for FieldValue {
+    fn from(v: bool) -> FieldValue {
+        FieldValue::Bool(v)
+    }
+}
+
+impl From<&str> for FieldValue {
+    fn from(v: &str) -> FieldValue {
+        FieldValue::Str(v.to_string())
+    }
+}
+
+impl From<String> for FieldValue {
+    fn from(v: String) -> FieldValue {
+        FieldValue::Str(v)
+    }
+}
+
+/// File header: metadata and column descriptors.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Header {
+    pub metadata: Vec<(String, String)>,
+    pub columns: Vec<Column>,
+}
+
+impl Header {
+    /// Looks up a metadata value.
+    pub fn meta(&self, key: &str) -> Option<&str> {
+        self.metadata.iter().find(|m| m.0 == key).map(|m| &*m.1)
+    }
+
+    /// Looks up a column index by name.
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.name == name)
+    }
+}
+
+/// Marks a row as the beginning or the end of a span. The event label is the
+/// span name.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpanEdge {
+    Begin { id: u64, parent: Option<u64> },
+    End { id: u64 },
+}
+
+impl SpanEdge {
+    fn parse(token: &str) -> Option<ReadingsResult<SpanEdge>> {
+        let err = || ReadingsError::Format(format!("Malformed span: {}", token));
+        if let Some(ids) = token.strip_prefix("begin=") {
+            let mut ids = ids.splitn(2, '/');
+            let id = ids.next().and_then(|id| id.parse().ok());
+            let parent = ids.next().map(|p| p.parse().ok());
+            Some(match (id, parent) {
+                (Some(id), None) => Ok(SpanEdge::Begin { id, parent: None }),
+                (Some(id), Some(Some(parent))) => Ok(SpanEdge::Begin {
+                    id,
+                    parent: Some(parent),
+                }),
+                _ => Err(err()),
+            })
+        } else {
+            token
+                .strip_prefix("end=")
+                .map(|id| id.parse().map(|id| SpanEdge::End { id }).map_err(|_| err()))
+        }
+    }
+}
+
+impl fmt::Display for SpanEdge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SpanEdge::Begin { id, parent: None } => write!(f, "begin={}", id),
+            SpanEdge::Begin {
+                id,
+                parent: Some(parent),
+            } => write!(f, "begin={}/{}", id, parent),
+            SpanEdge::End { id } => write!(f, "end={}", id),
+        }
+    }
+}
+
+/// A line of readings: the value of every column and an optional event label.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Row {
+    pub values: Vec<Value>,
+    pub event: Option<String>,
+    /// Key/value fields of the event, or of the line for rows without an
+    /// event (such as the `error` of a panicking gauge).
+    pub fields: Vec<(String, FieldValue)>,
+    /// Span begin or end marker. Only written for rows with an event.
+    pub span: Option<SpanEdge>,
+}
+
+/// CPU time consumed by a thread.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadCpu {
+    pub tid: u64,
+    pub name: String,
+    /// CPU time in userland (in s)
+    pub user_time: f64,
+    /// CPU time in kernel (in s)
+    pub system_time: f64,
+}
+
+/// CPU times of all the threads of the process at a given time.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadSample {
+    pub time: f64,
+    pub threads: Vec<ThreadCpu>,
+}
+
+/// An entire readings file, loaded in memory.
+#[derive(Clone, Debug, Default)]
+pub struct Recording {
+    pub header: Header,
+    pub rows: Vec<Row>,
+    /// Number of samples dropped by the probe.
+    pub dropped: u64,
+    /// Per-thread CPU times, if the probe recorded them.
+    pub threads: Vec<ThreadSample>,
+}
+
+/// Escapes a name or a label so that it does not contain whitespace, nor `=`.
+///
+/// The empty string is written as `\0`, so that it still makes a token.
+pub fn escape(s: &str) -> String {
+    if s.is_empty() {
+        return "\\0".to_string();
+    }
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            ' ' => escaped.push_str("\\s"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\\' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Reverts `escape`.
+pub fn unescape(s: &str) -> String {
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('s') => unescaped.push(' '),
+                Some('t') => unescaped.push('\t'),
+                Some('n') => unescaped.push('\n'),
+                Some('r') => unescaped.push('\r'),
+                Some('0') => (),
+                Some(c) => unescaped.push(c),
+                None => (),
+            }
+        } else {
+            unescaped.push(c)
+        }
+    }
+    unescaped
+}
+
+/// Writes a V2 header.
+pub fn write_header<W: Write>(w: &mut W, header: &Header) -> io::Result<()> {
+    writeln!(w, "{}", V2_MAGIC)?;
+    for (key, value) in &header.metadata {
+        writeln!(w, "#meta {} {}", escape(key), escape(value))?;
+    }
+    for c in &header.columns {
+        writeln!(
+            w,
+            "#column {} {} {} {} {}",
+            escape(&c.name),
+            c.ty.name(),
+            escape(&c.unit),
+            c.kind.name(),
+            escape(&c.source)
+        )?;
+    }
+    Ok(())
+}
+
+/// Writes a V2 row.
+pub fn write_row<W: Write>(w: &mut W, row: &Row) -> io::Result<()> {
+    for (ix, value) in row.values.iter().enumerate() {
+        if ix > 0 {
+            write!(w, " ")?;
+        }
+        match value {
+            Value::I64(v) => write!(w, "{:>10}", v)?,
+            Value::U64(v) => write!(w, "{:>10}", v)?,
+            Value::F64(v) => write!(w, "{:>10}", v)?,
+        }
+    }
+    if let Some(event) = &row.event {
+        write!(w, " {}", escape(event))?;
+        if let Some(span) = &row.span {
+            write!(w, " {}", span)?;
+        }
+    }
+    // an escaped event label has no bare `=`, so fields of a row without an
+    // event are not mistaken for its label
+    for (key, value) in &row.fields {
+        write!(
+            w,
+            " {}:{}={}",
+            escape(key),
+            value.type_name(),
+            escape(&value.to_string())
+        )?;
+    }
+    writeln!(w)
+}
+
+/// Writes a dropped samples record.
+pub fn write_dropped<W: Write>(w: &mut W, count: u64) -> io::Result<()> {
+    writeln!(w, "#dropped {}", count)
+}
+
+/// Writes a per-thread CPU times record.
+pub fn write_threads<W: Write>(w: &mut W, sample: &ThreadSample) -> io::Result<()> {
+    write!(w, "#threads {}", sample.time)?;
+    for t in &sample.threads {
+        write!(
+            w,
+            " {}:{}:{}:{}",
+            t.tid,
+            t.user_time,
+            t.system_time,
+            escape(&t.name)
+        )?;
+    }
+    writeln!(w)
+}
+
+fn parse_threads(line: &str) -> ReadingsResult<ThreadSample> {
+    let err = || ReadingsError::Format(format!("Malformed threads: {}", line));
+    let mut tokens = line.split_whitespace().skip(1);
+    let time = tokens.next().and_then(|t| t.parse().ok()).ok_or_else(err)?;
+    let threads = tokens
+        .map(|token| {
+            let mut parts = token.splitn(4, ':');
+            Ok(ThreadCpu {
+                tid: parts.next().and_then(|t| t.parse().ok()).ok_or_else(err)?,
+                user_time: parts.next().and_then(|t| t.parse().ok()).ok_or_else(err)?,
+                system_time: parts.next().and_then(|t| t.parse().ok()).ok_or_else(err)?,
+                name: parts.next().map(unescape).ok_or_else(err)?,
+            })
+        })
+        .collect::<ReadingsResult<Vec<_>>>()?;
+    Ok(ThreadSample { time, threads })
+}
+
+/// Encoding of the rows in a V2 file.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    /// Human readable, whitespace separated values.
+    #[default]
+    Text,
+    /// Delta and varint encoded values, see `binary`.
+    Binary,
+}
+
+/// Stateful writer for one of the encodings.
+pub enum Encoder {
+    Text,
+    Binary(binary::BinaryEncoder),
+}
+
+impl Encoder {
+    pub fn new(encoding: Encoding) -> Encoder {
+        match encoding {
+            Encoding::Text => Encoder::Text,
+            Encoding::Binary => Encoder::Binary(binary::BinaryEncoder::default()),
+        }
+    }
+
+    pub fn write_header<W: Write>(&mut self, w: &mut W, header: &Header) -> io::Result<()> {
+        match self {
+            Encoder::Text => write_header(w, header),
+            Encoder::Binary(b) => b.write_header(w, header),
+        }
+    }
+
+    pub fn write_row<W: Write>(&mut self, w: &mut W, row: &Row) -> io::Result<()> {
+        match self {
+            Encoder::Text => write_row(w, row),
+            Encoder::Binary(b) => b.write_row(w, row),
+        }
+    }
+
+    pub fn write_dropped<W: Write>(&mut self, w: &mut W, count: u64) -> io::Result<()> {
+        match self {
+            Encoder::Text => write_dropped(w, count),
+            Encoder::Binary(b) => b.write_dropped(w, count),
+        }
+    }
+
+    pub fn write_threads<W: Write>(&mut self, w: &mut W, sample: &ThreadSample) -> io::Result<()> {
+        match self {
+            Encoder::Text => write_threads(w, sample),
+            Encoder::Binary(b) => b.write_threads(w, sample),
+        }
+    }
+}
+
+/// Reads a single text line, without its line terminator.
+fn read_line<R: BufRead>(r: &mut R) -> ReadingsResult<Option<String>> {
+    let mut line = vec![];
+    if r.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    while line.last() == Some(&b'\n') || line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    String::from_utf8(line)
+        .map(Some)
+        .map_err(|_| ReadingsError::Format("Invalid UTF-8 in text line".to_string()))
+}
+
+/// Reads a V1 or V2 readings file, in text or binary encoding.
+///
+/// ```rust
+/// use readings_probe::format::*;
+///
+/// let header = Header {
+///     metadata: vec![("program".to_string(), "my program".to_string())],
+///     columns: vec![
+///         Column::new("time", ValueType::F64, "s", Kind::Gauge, "probe"),
+///         Column::new("queue size", ValueType::I64, "-", Kind::Gauge, "user"),
+///     ],
+/// };
+/// let row = Row {
+///     values: vec![Value::F64(0.5), Value::I64(-12)],
+///     event: Some("batch done".to_string()),
+///     fields: vec![
+///         ("file".to_string(), "input 1.csv".into()),
+///         ("size".to_string(), 128u64.into()),
+///     ],
+///     span: None,
+/// };
+/// let mut buffer = vec![];
+/// write_header(&mut buffer, &header).unwrap();
+/// write_row(&mut buffer, &row).unwrap();
+///
+/// let recording = read(&*buffer).unwrap();
+/// assert_eq!(recording.header, header);
+/// assert_eq!(recording.rows, vec![row]);
+/// ```
+pub fn read<R: BufRead>(mut r: R) -> ReadingsResult<Recording> {
+    let magic =
+        read_line(&mut r)?.ok_or_else(|| ReadingsError::Format("Empty file".to_string()))?;
+    match &*magic {
+        V1_MAGIC => read_v1(r.lines()),
+        V2_MAGIC => read_v2(r),
+        _ => Err(ReadingsError::Format(format!("Unknown format: {}", magic))),
+    }
+}
+
+fn parse_row(header: &Header, line: &str) -> ReadingsResult<Row> {
+    let mut tokens = line.split_whitespace();
+    let values = header
+        .columns
+        .iter()
+        .map(|c| {
+            let token = tokens
+                .next()
+                .ok_or_else(|| ReadingsError::Format(format!("Truncated line: {}", line)))?;
+            c.ty.parse(token)
+        })
+        .collect::<ReadingsResult<Vec<Value>>>()?;
+    let mut tokens = tokens.peekable();
+    let event = match tokens.peek() {
+        Some(token) if parse_field(token).is_none() => tokens.next().map(unescape),
+        _ => None,
+    };
+    let mut fields = vec![];
+    let mut span = None;
+    for token in tokens {
+        if let Some(edge) = SpanEdge::parse(token) {
+            span = Some(edge?);
+        } else if let Some(field) = parse_field(token) {
+            fields.push(field?);
+        }
+    }
+    Ok(Row {
+        values,
+        event,
+        fields,
+        span,
+    })
+}
+
+/// Parses a `<key>:<type>=<value>` field token. Returns None for tokens that
+/// are not fields.
+fn parse_field(token: &str) -> Option<ReadingsResult<(String, FieldValue)>> {
+    // the first unescaped `=` separates the key and type from the value
+    let mut escaped = false;
+    let eq = token.char_indices().find_map(|(ix, c)| match c {
+        '\\' => {
+            escaped = !escaped;
+            None
+        }
+        '=' if !escaped => Some(ix),
+        _ => {
+            escaped = false;
+            None
+        }
+    })?;
+    let (key, ty) = token[..eq].rsplit_once(':')?;
+    Some(FieldValue::parse(ty, &unescape(&token[eq + 1..])).map(|v| (unescape(key), v)))
+}
+
+fn read_v2<R: BufRead>(mut r: R) -> ReadingsResult<Recording> {
+    let mut recording = Recording::default();
+    while let Some(line) = read_line(&mut r)? {
+        if line == binary::BINARY_MARKER {
+            binary::read_rows(&mut r, &mut recording)?;
+            break;
+        } else if line.starts_with("#meta ") {
+            let mut tokens = line.split_whitespace().skip(1);
+            let key = tokens.next().map(unescape).unwrap_or_default();
+            let value = tokens.next().map(unescape).unwrap_or_default();
+            recording.header.metadata.push((key, value));
+        } else if line.starts_with("#column ") {
+            let tokens: Vec<&str> = line.split_whitespace().skip(1).collect();
+            if tokens.len() < 5 {
+                return Err(ReadingsError::Format(format!("Malformed column: {}", line)));
+            }
+            recording.header.columns.push(Column {
+                name: unescape(tokens[0]),
+                ty: tokens[1].parse()?,
+                unit: unescape(tokens[2]),
+                kind: tokens[3].parse()?,
+                source: unescape(tokens[4]),
+            });
+        } else if line.starts_with("#threads ") {
+            recording.threads.push(parse_threads(&line)?);
+        } else if let Some(count) = line.strip_prefix("#dropped ") {
+            recording.dropped += count
+                .trim()
+                .parse::<u64>()
+                .map_err(|_| ReadingsError::Format(format!("Malformed dropped: {}", line)))?;
+        } else if !line.starts_with('#') && !line.trim().is_empty() {
+            let row = parse_row(&recording.header, &line)?;
+            recording.rows.push(row);
+        }
+    }
+    Ok(recording)
+}
+
+/// Column descriptors of the V1 fixed columns.
+fn v1_columns() -> Vec<Column> {
+    use self::Kind::*;
+    use self::ValueType::*;
+    vec![
+        Column::new("time", F64, "s", Gauge, "probe"),
+        Column::new("cores", U64, "-", Gauge, "probe"),
+        Column::new("vsz", U64, "B", Gauge, "os"),
+        Column::new("rsz", U64, "B", Gauge, "os"),
+        Column::new("rszmax", U64, "B", Gauge, "os"),
+        Column::new("utime", F64, "s", Counter, "os"),
+        Column::new("stime", F64, "s", Counter, "os"),
+        Column::new("minf", U64, "-", Counter, "os"),
+        Column::new("majf", U64, "-", Counter, "os"),
+        Column::new("alloc", U64, "B", Counter, "alloc"),
+        Column::new("free", U64, "B", Counter, "alloc"),
+    ]
+}
+
+fn read_v1<I: Iterator<Item = io::Result<String>>>(lines: I) -> ReadingsResult<Recording> {
+    let mut recording = Recording::default();
+    let mut header_found = false;
+    for line in lines {
+        let line = line?;
+        if let Some(meta) = line.strip_prefix('#') {
+            let mut tokens = meta.splitn(2, ' ');
+            let key = tokens.next().unwrap_or("").to_string();
+            let value = tokens.next().unwrap_or("").to_string();
+            recording.header.metadata.push((key, value));
+        } else if !header_found {
+            header_found = true;
+            recording.header.columns = v1_columns();
+            let mut names: Vec<&str> = line.split_whitespace().skip(11).collect();
+            names.pop(); // "event"
+            for name in names {
+                let column = Column::new(name, ValueType::I64, "-", Kind::Gauge, "user");
+                recording.header.columns.push(column);
+            }
+        } else if !line.trim().is_empty() {
+            let row = parse_row(&recording.header, &line)?;
+            recording.rows.push(row);
+        }
+    }
+    Ok(recording)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escape_round_trip() {
+        for s in &[
+            "",
+            " ",
+            "a b",
+            "\\",
+            "\\0",
+            "a=b",
+            "=",
+            "line\nbreak\r\n",
+            "\t",
+        ] {
+            let escaped = escape(s);
+            assert!(!escaped.is_empty());
+            assert!(!escaped.contains(char::is_whitespace));
+            assert_eq!(unescape(&escaped), *s);
+            // an escaped `=` does not split a field
+            let field = format!("{}:str={}", escaped, escaped);
+            let (key, value) = parse_field(&field).unwrap().unwrap();
+            assert_eq!(key, *s);
+            assert_eq!(value, FieldValue::Str(s.to_string()));
+        }
+    }
+
+    #[test]
+    fn empty_strings_round_trip() {
+        let header = Header {
+            metadata: vec![("".to_string(), "".to_string())],
+            columns: vec![
+                Column::new("time", ValueType::F64, "s", Kind::Gauge, "probe"),
+                Column::new("", ValueType::I64, "", Kind::Gauge, "user"),
+            ],
+        };
+        let row = Row {
+            values: vec![Value::F64(0.5), Value::I64(3)],
+            event: Some("".to_string()),
+            fields: vec![("".to_string(), "".into())],
+            span: None,
+        };
+        let mut buffer = vec![];
+        write_header(&mut buffer, &header).unwrap();
+        write_row(&mut buffer, &row).unwrap();
+        let recording = read(&*buffer).unwrap();
+        assert_eq!(recording.header, header);
+        assert_eq!(recording.rows, vec![row]);
+    }
+
+    #[test]
+    fn fields_without_event_round_trip() {
+        let header = Header {
+            metadata: vec![],
+            columns: vec![Column::new(
+                "time",
+                ValueType::F64,
+                "s",
+                Kind::Gauge,
+                "probe",
+            )],
+        };
+        let fields = vec![("error".to_string(), "gauge panicked: boom".into())];
+        let rows = vec![
+            Row {
+                values: vec![Value::F64(0.5)],
+                event: None,
+                fields: fields.clone(),
+                span: None,
+            },
+            // an event label looking like a field
+            Row {
+                values: vec![Value::F64(1.0)],
+                event: Some("error:str=boom".to_string()),
+                fields,
+                span: None,
+            },
+        ];
+        let mut buffer = vec![];
+        write_header(&mut buffer, &header).unwrap();
+        for row in &rows {
+            write_row(&mut buffer, row).unwrap();
+        }
+        let recording = read(&*buffer).unwrap();
+        assert_eq!(recording.rows, rows);
+    }
+
+    #[test]
+    fn v1_names_may_contain_colons() {
+        let v1 = "#ReadingsV1\n   time cor        vsz        rsz     rszmax    utime    stime       minf       majf      alloc       free   http:200 event\n  0.001   4   10000   2000   2000 0.001000 0.000000         12          0        100         50          7 start\n";
+        let recording = read(v1.as_bytes()).unwrap();
+        let column = recording.header.columns.last().unwrap();
+        assert_eq!(column.name, "http:200");
+        assert_eq!((column.ty, column.kind), (ValueType::I64, Kind::Gauge));
+        assert_eq!(recording.rows[0].values.last(), Some(&Value::I64(7)));
+        assert_eq!(recording.rows[0].event.as_deref(), Some("start"));
+    }
+}
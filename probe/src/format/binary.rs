@@ -0,0 +1,477 @@
+//! Binary encoding of the V2 rows.
+//!
+//! The header is the same as in the text encoding, and is followed by a
+//! `#binary` line. Then come records, each starting with a tag byte:
+//!
+//! * `ROW`: one varint per column, then the event label as a varint (0 for
+//!   no event, or the string id plus one). Integers are encoded as the
+//!   zigzagged difference to the previous row, floats as the zigzagged
+//!   difference of their bit patterns.
+//! * `STRING`: a string table entry: id and length as varints, then the
+//!   UTF-8 bytes. Entries are written before the first row using them.
+//! * `FIELDS`: the fields of the preceding row: their count, then for
+//!   each the key string id, a type byte and the value. Integers are
+//!   varints (zigzagged for i64), floats their 8 little-endian bytes, bools a
+//!   byte and strings their length as a varint then their UTF-8 bytes. Field
+//!   values are not in the string table, which would grow without bound
+//!   with values such as file names.
+//! * `SPAN`: marks the preceding row as a span edge: 0 and the span id then
+//!   the parent id plus one (0 for no parent) for a begin, 1 and the span id
+//!   for an end.
+//! * `DROPPED`: a number of dropped samples, as a varint.
+//! * `THREADS`: per-thread CPU times: the time as 8 little-endian bytes, the
+//!   number of threads, then for each its id, its name string id, and its
+//!   user and system times as 8 little-endian bytes.
+//!
+//! ```rust
+//! use readings_probe::format::*;
+//!
+//! let header = Header {
+//!     metadata: vec![],
+//!     columns: vec![
+//!         Column::new("time", ValueType::F64, "s", Kind::Gauge, "probe"),
+//!         Column::new("delta", ValueType::I64, "-", Kind::Gauge, "user"),
+//!     ],
+//! };
+//! let rows = vec![
+//!     Row {
+//!         values: vec![Value::F64(0.0), Value::I64(12)],
+//!         event: Some("start".into()),
+//!         fields: vec![("batch".into(), 3u64.into()), ("model".into(), "resnet".into())],
+//!         span: Some(SpanEdge::Begin { id: 1, parent: None }),
+//!     },
+//!     Row { values: vec![Value::F64(0.1), Value::I64(-3)], ..Row::default() },
+//!     Row {
+//!         values: vec![Value::F64(0.2), Value::I64(i64::MIN)],
+//!         event: Some("start".into()),
+//!         fields: vec![("ratio".into(), (-0.5).into()), ("warm".into(), true.into())],
+//!         span: Some(SpanEdge::End { id: 1 }),
+//!     },
+//! ];
+//! let mut buffer = vec![];
+//! let mut encoder = Encoder::new(Encoding::Binary);
+//! encoder.write_header(&mut buffer, &header).unwrap();
+//! for row in &rows {
+//!     encoder.write_row(&mut buffer, row).unwrap();
+//! }
+//!
+//! let recording = read(&*buffer).unwrap();
+//! assert_eq!(recording.header, header);
+//! assert_eq!(recording.rows, rows);
+//! ```
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufRead, Read, Write};
+
+use super::{
+    FieldValue, Header, Recording, Row, SpanEdge, ThreadCpu, ThreadSample, Value, ValueType,
+};
+use crate::{ReadingsError, ReadingsResult};
+
+/// Marker line between the header and the binary records.
+pub const BINARY_MARKER: &str = "#binary";
+
+const ROW: u8 = 1;
+const STRING: u8 = 2;
+const DROPPED: u8 = 3;
+const FIELDS: u8 = 4;
+const SPAN: u8 = 5;
+const THREADS: u8 = 6;
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    ((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn write_varint<W: Write>(w: &mut W, mut v: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    loop {
+        let byte = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf[len] = byte;
+            len += 1;
+            break;
+        }
+        buf[len] = byte | 0x80;
+        len += 1;
+    }
+    w.write_all(&buf[..len])
+}
+
+fn read_u8<R: BufRead>(r: &mut R) -> ReadingsResult<u8> {
+    let mut byte = [0u8];
+    r.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_f64<R: BufRead>(r: &mut R) -> ReadingsResult<f64> {
+    let mut bytes = [0u8; 8];
+    r.read_exact(&mut bytes)?;
+    Ok(f64::from_le_bytes(bytes))
+}
+
+/// Reads a string written as its length then its UTF-8 bytes.
+fn read_string<R: BufRead>(r: &mut R) -> ReadingsResult<String> {
+    // the length may be corrupted: do not trust it for the allocation
+    let len = read_varint(r)?;
+    let mut bytes = vec![];
+    r.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(ReadingsError::Format("Truncated string".to_string()));
+    }
+    String::from_utf8(bytes).map_err(|_| ReadingsError::Format("Invalid string".to_string()))
+}
+
+fn read_varint<R: BufRead>(r: &mut R) -> ReadingsResult<u64> {
+    let mut v = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(r)?;
+        v |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(v);
+        }
+    }
+    Err(ReadingsError::Format("Varint overflow".to_string()))
+}
+
+fn bits(value: &Value) -> u64 {
+    match *value {
+        Value::I64(v) => v as u64,
+        Value::U64(v) => v,
+        Value::F64(v) => v.to_bits(),
+    }
+}
+
+fn from_bits(ty: ValueType, bits: u64) -> Value {
+    match ty {
+        ValueType::I64 => Value::I64(bits as i64),
+        ValueType::U64 => Value::U64(bits),
+        ValueType::F64 => Value::F64(f64::from_bits(bits)),
+    }
+}
+
+/// Stateful binary encoder: keeps the previous row and the string table of
+/// the event labels, field keys and thread names.
+#[derive(Default)]
+pub struct BinaryEncoder {
+    previous: Vec<u64>,
+    strings: HashMap<String, u64>,
+}
+
+impl BinaryEncoder {
+    pub fn write_header<W: Write>(&mut self, w: &mut W, header: &Header) -> io::Result<()> {
+        super::write_header(w, header)?;
+        writeln!(w, "{}", BINARY_MARKER)?;
+        self.previous = vec![0; header.columns.len()];
+        Ok(())
+    }
+
+    fn string_id<W: Write>(&mut self, w: &mut W, s: &str) -> io::Result<u64> {
+        if let Some(id) = self.strings.get(s) {
+            return Ok(*id);
+        }
+        let id = self.strings.len() as u64;
+        w.write_all(&[STRING])?;
+        write_varint(w, id)?;
+        write_varint(w, s.len() as u64)?;
+        w.write_all(s.as_bytes())?;
+        self.strings.insert(s.to_string(), id);
+        Ok(id)
+    }
+
+    pub fn write_row<W: Write>(&mut self, w: &mut W, row: &Row) -> io::Result<()> {
+        let event = match &row.event {
+            Some(event) => self.string_id(w, event)? + 1,
+            None => 0,
+        };
+        w.write_all(&[ROW])?;
+        for (value, previous) in row.values.iter().zip(self.previous.iter_mut()) {
+            let bits = bits(value);
+            write_varint(w, zigzag(bits.wrapping_sub(*previous) as i64))?;
+            *previous = bits;
+        }
+        write_varint(w, event)?;
+        if !row.fields.is_empty() {
+            self.write_fields(w, &row.fields)?;
+        }
+        match row.span {
+            Some(SpanEdge::Begin { id, parent }) if row.event.is_some() => {
+                w.write_all(&[SPAN, 0])?;
+                write_varint(w, id)?;
+                write_varint(w, parent.map(|p| p + 1).unwrap_or(0))
+            }
+            Some(SpanEdge::End { id }) if row.event.is_some() => {
+                w.write_all(&[SPAN, 1])?;
+                write_varint(w, id)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn write_fields<W: Write>(
+        &mut self,
+        w: &mut W,
+        fields: &[(String, FieldValue)],
+    ) -> io::Result<()> {
+        // string table entries must precede the record using them
+        let mut ids = Vec::with_capacity(fields.len());
+        for (key, _) in fields {
+            ids.push(self.string_id(w, key)?);
+        }
+        w.write_all(&[FIELDS])?;
+        write_varint(w, fields.len() as u64)?;
+        for ((_, value), key_id) in fields.iter().zip(ids) {
+            write_varint(w, key_id)?;
+            match value {
+                FieldValue::I64(v) => {
+                    w.write_all(&[0])?;
+                    write_varint(w, zigzag(*v))?;
+                }
+                FieldValue::U64(v) => {
+                    w.write_all(&[1])?;
+                    write_varint(w, *v)?;
+                }
+                FieldValue::F64(v) => {
+                    w.write_all(&[2])?;
+                    w.write_all(&v.to_le_bytes())?;
+                }
+                FieldValue::Bool(v) => w.write_all(&[3, *v as u8])?,
+                FieldValue::Str(s) => {
+                    w.write_all(&[4])?;
+                    write_varint(w, s.len() as u64)?;
+                    w.write_all(s.as_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_dropped<W: Write>(&mut self, w: &mut W, count: u64) -> io::Result<()> {
+        w.write_all(&[DROPPED])?;
+        write_varint(w, count)
+    }
+
+    pub fn write_threads<W: Write>(&mut self, w: &mut W, sample: &ThreadSample) -> io::Result<()> {
+        let mut names = Vec::with_capacity(sample.threads.len());
+        for t in &sample.threads {
+            names.push(self.string_id(w, &t.name)?);
+        }
+        w.write_all(&[THREADS])?;
+        w.write_all(&sample.time.to_le_bytes())?;
+        write_varint(w, sample.threads.len() as u64)?;
+        for (t, name) in sample.threads.iter().zip(names) {
+            write_varint(w, t.tid)?;
+            write_varint(w, name)?;
+            w.write_all(&t.user_time.to_le_bytes())?;
+            w.write_all(&t.system_time.to_le_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+fn string(strings: &[String], id: u64) -> ReadingsResult<String> {
+    strings
+        .get(id as usize)
+        .cloned()
+        .ok_or_else(|| ReadingsError::Format(format!("Unknown string {}", id)))
+}
+
+fn last_row(recording: &mut Recording) -> ReadingsResult<&mut Row> {
+    recording
+        .rows
+        .last_mut()
+        .ok_or_else(|| ReadingsError::Format("Row annotation before any row".to_string()))
+}
+
+/// Reads binary records until the end of the stream.
+pub(crate) fn read_rows<R: BufRead>(r: &mut R, recording: &mut Recording) -> ReadingsResult<()> {
+    let types: Vec<ValueType> = recording.header.columns.iter().map(|c| c.ty).collect();
+    let mut previous = vec![0u64; types.len()];
+    let mut strings: Vec<String> = vec![];
+    while !r.fill_buf()?.is_empty() {
+        match read_u8(r)? {
+            STRING => {
+                let id = read_varint(r)? as usize;
+                let s = read_string(r)?;
+                if id != strings.len() {
+                    return Err(ReadingsError::Format(format!(
+                        "Unexpected string id {}",
+                        id
+                    )));
+                }
+                strings.push(s);
+            }
+            ROW => {
+                let mut values = Vec::with_capacity(types.len());
+                for (ty, previous) in types.iter().zip(previous.iter_mut()) {
+                    *previous = previous.wrapping_add(unzigzag(read_varint(r)?) as u64);
+                    values.push(from_bits(*ty, *previous));
+                }
+                let event = match read_varint(r)? {
+                    0 => None,
+                    id => Some(string(&strings, id - 1)?),
+                };
+                recording.rows.push(Row {
+                    values,
+                    event,
+                    ..Row::default()
+                });
+            }
+            FIELDS => {
+                let count = read_varint(r)?;
+                let mut fields = vec![];
+                for _ in 0..count {
+                    let key = string(&strings, read_varint(r)?)?;
+                    let value = match read_u8(r)? {
+                        0 => FieldValue::I64(unzigzag(read_varint(r)?)),
+                        1 => FieldValue::U64(read_varint(r)?),
+                        2 => FieldValue::F64(read_f64(r)?),
+                        3 => FieldValue::Bool(read_u8(r)? != 0),
+                        4 => FieldValue::Str(read_string(r)?),
+                        ty => {
+                            return Err(ReadingsError::Format(format!("Unknown field type {}", ty)))
+                        }
+                    };
+                    fields.push((key, value));
+                }
+                last_row(recording)?.fields = fields;
+            }
+            SPAN => {
+                let edge = match read_u8(r)? {
+                    0 => SpanEdge::Begin {
+                        id: read_varint(r)?,
+                        parent: read_varint(r)?.checked_sub(1),
+                    },
+                    1 => SpanEdge::End {
+                        id: read_varint(r)?,
+                    },
+                    edge => {
+                        return Err(ReadingsError::Format(format!("Unknown span edge {}", edge)))
+                    }
+                };
+                last_row(recording)?.span = Some(edge);
+            }
+            DROPPED => recording.dropped += read_varint(r)?,
+            THREADS => {
+                let time = read_f64(r)?;
+                let mut threads = vec![];
+                for _ in 0..read_varint(r)? {
+                    threads.push(ThreadCpu {
+                        tid: read_varint(r)?,
+                        name: string(&strings, read_varint(r)?)?,
+                        user_time: read_f64(r)?,
+                        system_time: read_f64(r)?,
+                    });
+                }
+                recording.threads.push(ThreadSample { time, threads });
+            }
+            tag => return Err(ReadingsError::Format(format!("Unknown record tag {}", tag))),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{read, Column, Encoder, Encoding, Kind};
+    use super::*;
+
+    fn header() -> Header {
+        Header {
+            metadata: vec![("program".to_string(), "test".to_string())],
+            columns: vec![
+                Column::new("time", ValueType::F64, "s", Kind::Gauge, "probe"),
+                Column::new("rsz", ValueType::U64, "B", Kind::Gauge, "os"),
+                Column::new("delta", ValueType::I64, "-", Kind::Gauge, "user"),
+            ],
+        }
+    }
+
+    fn row(time: f64, event: Option<&str>) -> Row {
+        Row {
+            values: vec![Value::F64(time), Value::U64(1 << 20), Value::I64(-7)],
+            event: event.map(|e| e.to_string()),
+            ..Row::default()
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut rows = vec![
+            row(0.0, None),
+            row(0.1, Some("load")),
+            row(0.2, Some("load")),
+        ];
+        rows[1].fields = vec![
+            ("file".to_string(), "input 1.csv".into()),
+            ("size".to_string(), 128u64.into()),
+            ("delta".to_string(), (-3i64).into()),
+            ("ratio".to_string(), 0.25.into()),
+            ("warm".to_string(), false.into()),
+            ("empty".to_string(), "".into()),
+        ];
+        rows[1].span = Some(SpanEdge::Begin {
+            id: 2,
+            parent: Some(1),
+        });
+        rows[2].span = Some(SpanEdge::End { id: 2 });
+        let threads = ThreadSample {
+            time: 0.15,
+            threads: vec![ThreadCpu {
+                tid: 12,
+                name: "worker 1".to_string(),
+                user_time: 0.5,
+                system_time: 0.125,
+            }],
+        };
+
+        let mut buffer = vec![];
+        let mut encoder = Encoder::new(Encoding::Binary);
+        encoder.write_header(&mut buffer, &header()).unwrap();
+        encoder.write_row(&mut buffer, &rows[0]).unwrap();
+        encoder.write_row(&mut buffer, &rows[1]).unwrap();
+        encoder.write_threads(&mut buffer, &threads).unwrap();
+        encoder.write_dropped(&mut buffer, 3).unwrap();
+        encoder.write_row(&mut buffer, &rows[2]).unwrap();
+        encoder.write_dropped(&mut buffer, 2).unwrap();
+
+        let recording = read(&*buffer).unwrap();
+        assert_eq!(recording.header, header());
+        assert_eq!(recording.rows, rows);
+        assert_eq!(recording.threads, vec![threads]);
+        assert_eq!(recording.dropped, 5);
+    }
+
+    #[test]
+    fn field_values_are_not_interned() {
+        let mut buffer = vec![];
+        let mut encoder = BinaryEncoder::default();
+        encoder.write_header(&mut buffer, &header()).unwrap();
+        let mut rows = vec![];
+        for i in 0..100 {
+            let mut row = row(i as f64, Some("open"));
+            row.fields = vec![("file".to_string(), format!("file{}.csv", i).into())];
+            encoder.write_row(&mut buffer, &row).unwrap();
+            rows.push(row);
+        }
+        // the event label and the field key
+        assert_eq!(encoder.strings.len(), 2);
+        assert_eq!(read(&*buffer).unwrap().rows, rows);
+    }
+
+    #[test]
+    fn corrupted_string_length() {
+        let mut bytes = vec![];
+        write_varint(&mut bytes, u64::MAX >> 1).unwrap();
+        bytes.extend_from_slice(b"short");
+        match read_string(&mut &*bytes) {
+            Err(ReadingsError::Format(_)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+}
@@ -7,8 +7,8 @@
 //!
 //! log_event() and get_i64 will fail silently if the probe has not be `set`, allowing to
 //! toggle on or off the instrumentation at the top level.
-use crate::{Probe, ReadingsResult};
-use std::sync::atomic::AtomicI64;
+use crate::{AtomicF64, FieldValue, Histogram, Probe, ReadingsResult, SpanGuard};
+use std::sync::atomic::{AtomicI64, AtomicU64};
 use std::sync::{Arc, Mutex};
 
 lazy_static::lazy_static! {
@@ -39,6 +39,27 @@ pub fn log_event(event: &str) -> ReadingsResult<()> {
     Ok(())
 }
 
+/// Log on the default probe an individual event with a label, key/value fields and the
+/// current values of metrics.
+pub fn log_event_with(event: &str, fields: &[(&str, FieldValue)]) -> ReadingsResult<()> {
+    if let Ok(mut lock) = PROBE.lock() {
+        if let Some(probe) = lock.as_mut() {
+            return probe.log_event_with(event, fields);
+        }
+    }
+    Ok(())
+}
+
+/// Open a span on the default probe. Returns None if the probe has not been `set`.
+pub fn span(name: &str) -> ReadingsResult<Option<SpanGuard>> {
+    if let Ok(mut lock) = PROBE.lock() {
+        if let Some(probe) = lock.as_mut() {
+            return probe.span(name).map(Some);
+        }
+    }
+    Ok(None)
+}
+
 /// Recover from the default probe a pre-registered used-defined metrics.
 pub fn get_i64<S: AsRef<str>>(name: S) -> Option<Arc<AtomicI64>> {
     if let Ok(mut lock) = PROBE.lock() {
@@ -48,3 +69,33 @@ pub fn get_i64<S: AsRef<str>>(name: S) -> Option<Arc<AtomicI64>> {
     }
     None
 }
+
+/// Recover from the default probe a pre-registered u64 used-defined metrics.
+pub fn get_u64<S: AsRef<str>>(name: S) -> Option<Arc<AtomicU64>> {
+    if let Ok(mut lock) = PROBE.lock() {
+        if let Some(probe) = lock.as_mut() {
+            return probe.get_u64(name);
+        }
+    }
+    None
+}
+
+/// Recover from the default probe a pre-registered f64 used-defined metrics.
+pub fn get_f64<S: AsRef<str>>(name: S) -> Option<Arc<AtomicF64>> {
+    if let Ok(mut lock) = PROBE.lock() {
+        if let Some(probe) = lock.as_mut() {
+            return probe.get_f64(name);
+        }
+    }
+    None
+}
+
+/// Recover from the default probe a pre-registered histogram.
+pub fn get_histogram<S: AsRef<str>>(name: S) -> Option<Arc<Histogram>> {
+    if let Ok(mut lock) = PROBE.lock() {
+        if let Some(probe) = lock.as_mut() {
+            return probe.get_histogram(name);
+        }
+    }
+    None
+}
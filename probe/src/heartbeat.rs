@@ -0,0 +1,112 @@
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread;
+use std::time;
+
+use crate::{Probe, ProbeData, ReadingsError, ReadingsResult};
+
+#[derive(Default)]
+pub(crate) struct HeartbeatControl {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl HeartbeatControl {
+    pub(crate) fn stop(&self) {
+        if let Ok(mut stopped) = self.stopped.lock() {
+            *stopped = true;
+            self.condvar.notify_all();
+        }
+    }
+
+    /// Sleeps until `deadline`. Returns false if the heartbeat was stopped
+    /// meanwhile.
+    fn sleep_until(&self, deadline: time::Instant) -> bool {
+        let mut stopped = match self.stopped.lock() {
+            Ok(stopped) => stopped,
+            Err(_) => return false,
+        };
+        loop {
+            if *stopped {
+                return false;
+            }
+            let now = time::Instant::now();
+            if now >= deadline {
+                return true;
+            }
+            stopped = match self.condvar.wait_timeout(stopped, deadline - now) {
+                Ok((stopped, _)) => stopped,
+                Err(_) => return false,
+            };
+        }
+    }
+}
+
+/// Handle on a heartbeat thread spawned by `Probe::spawn_heartbeat`.
+///
+/// Dropping the handle detaches the thread: it will keep on recording until
+/// the probe is finished or its last clone is dropped.
+///
+/// ```rust
+/// fn main() -> readings_probe::ReadingsResult<()> {
+///     let mut probe = readings_probe::ProbeBuilder::new(std::io::sink()).build()?;
+///     let heartbeat = probe.spawn_heartbeat(std::time::Duration::from_millis(10))?;
+///     probe.log_event("working")?;
+///     heartbeat.stop();
+///     heartbeat.join()
+/// }
+/// ```
+pub struct HeartbeatHandle {
+    control: Arc<HeartbeatControl>,
+    thread: thread::JoinHandle<ReadingsResult<()>>,
+}
+
+impl HeartbeatHandle {
+    pub(crate) fn spawn(
+        probe: Weak<ProbeData>,
+        origin: time::Instant,
+        interval: time::Duration,
+        control: Arc<HeartbeatControl>,
+    ) -> HeartbeatHandle {
+        let thread = {
+            let control = control.clone();
+            thread::spawn(move || {
+                for step in 1.. {
+                    if !control.sleep_until(origin + (step * interval)) {
+                        break;
+                    }
+                    match probe.upgrade() {
+                        Some(probe) => {
+                            if let Err(e) = Probe(probe).heartbeat() {
+                                eprintln!("{:?}", e);
+                            }
+                        }
+                        None => return Ok(()),
+                    }
+                }
+                match probe.upgrade() {
+                    Some(probe) => Probe(probe).write_stopped(),
+                    None => Ok(()),
+                }
+            })
+        };
+        HeartbeatHandle { control, thread }
+    }
+
+    /// Signals the heartbeat to stop, without waiting for it.
+    ///
+    /// The heartbeat thread writes a last "stopped" line and flushes the
+    /// probe before terminating.
+    pub fn stop(&self) {
+        self.control.stop()
+    }
+
+    /// Waits for the heartbeat thread to terminate.
+    ///
+    /// This will block until the heartbeat is stopped, either by `stop()`,
+    /// `Probe::finish()` or the drop of the last probe clone.
+    pub fn join(self) -> ReadingsResult<()> {
+        self.thread
+            .join()
+            .map_err(|_| ReadingsError::HeartbeatPanicked)?
+    }
+}
@@ -16,15 +16,16 @@
 //! readings_probe::instrumented_allocator!();
 //!
 //! fn main() -> readings_probe::ReadingsResult<()> {
-//!     // setup the probe
-//!     let mut probe =
-//!         readings_probe::Probe::new(std::fs::File::create("readings.out").unwrap()).unwrap();
+//!     // setup the probe (1sec i a lot. heartbeat can be realistically set as low as a few millis).
+//!     let mut builder =
+//!         readings_probe::ProbeBuilder::new(std::fs::File::create("readings.out").unwrap())
+//!             .heartbeat(std::time::Duration::from_millis(1000));
 //!
 //!     // We will use an AtomicI64 to communicate a user-defined metrics ("progress") to the probe.
-//!     let progress = probe.register_i64("progress".to_string())?;
+//!     let progress = builder.register_i64("progress");
 //!
-//!     // Starts the probe (1sec i a lot. heartbeat can be realistically set as low as a few millis).
-//!     probe.spawn_heartbeat(std::time::Duration::from_millis(1000))?;
+//!     // Starts the probe.
+//!     let probe = builder.start()?;
 //!
 //!     // do some stuff, update progress
 //!     let percent_done = 12;
@@ -43,18 +44,36 @@ extern crate lazy_static;
 
 /// allocator instrumentation
 pub mod alloc;
+mod builder;
+pub mod format;
 /// grobal default probe instance and associated macros
 pub mod global;
+mod heartbeat;
+mod metric;
+#[cfg(feature = "perf")]
+mod perf;
+mod source;
+mod span;
+mod writer;
 
 use std::io::Write;
-use std::sync::atomic::AtomicI64;
 use std::sync::atomic::Ordering::Relaxed;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{io, sync, time};
 
+use format::{Column, Header, Kind, Row, SpanEdge, ThreadCpu, ThreadSample, Value, ValueType};
+
 use thiserror::Error;
 
+pub use builder::ProbeBuilder;
+pub use format::FieldValue;
+pub use heartbeat::HeartbeatHandle;
+pub use metric::{AtomicF64, GaugeFn, Histogram, Metric};
+pub use source::{Builtin, ReadingSource};
+pub use span::SpanGuard;
+
 /// Reading error enumeration.
 #[derive(Error, Debug)]
 pub enum ReadingsError {
@@ -62,10 +81,32 @@ pub enum ReadingsError {
     LateRegistertingMetricsAttempt,
     #[error("io Error accessing /proc/self/stat")]
     ProcStat(io::Error),
+    #[error("io Error accessing /proc/self/io")]
+    ProcIo(io::Error),
+    #[error("io Error accessing /proc/self/status")]
+    ProcStatus(io::Error),
+    #[error("io Error accessing /proc/self/cgroup")]
+    ProcCgroup(io::Error),
+    #[error("io Error accessing /proc/self/task")]
+    ProcTask(io::Error),
+    #[error("Malformed stat file: {0}")]
+    MalformedStat(String),
+    #[error("Invalid value {value:?} for field {field} of stat file")]
+    InvalidStatField { field: usize, value: String },
+    #[error("Source sampled {got} values for {expected} columns")]
+    SourceValues { expected: usize, got: usize },
+    #[error("Too many allocation groups (at most {0})")]
+    TooManyAllocGroups(usize),
     #[error("Io error writing readings")]
     Io(#[from] io::Error),
     #[error("Poisoned probe")]
     PoisonedProbe,
+    #[error("Heartbeat thread panicked")]
+    HeartbeatPanicked,
+    #[error("Writer thread panicked")]
+    WriterPanicked,
+    #[error("Malformed readings file: {0}")]
+    Format(String),
 }
 
 /// Reading generic Result helper.
@@ -91,9 +132,155 @@ pub fn get_os_readings() -> ReadingsResult<OsReadings> {
     return macos::get_os_readings();
     #[cfg(target_os = "windows")]
     return windows::get_os_readings();
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "windows"
+    )))]
     return unsafe { Ok(std::mem::zeroed()) };
 }
 
+/// Returns the I/O counters of the process.
+///
+/// Only implemented on Linux and Android, where they are read from
+/// `/proc/self/io`: returns None on other systems.
+#[allow(unreachable_code)]
+pub fn get_io_readings() -> ReadingsResult<Option<IoReadings>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return linux::get_io_readings().map(Some);
+    Ok(None)
+}
+
+#[derive(Debug, Default)]
+pub struct IoReadings {
+    /// Bytes read, including from the page cache
+    pub rchar: u64,
+    /// Bytes written, including to the page cache
+    pub wchar: u64,
+    /// Bytes actually fetched from the storage layer
+    pub read_bytes: u64,
+    /// Bytes actually sent to the storage layer
+    pub write_bytes: u64,
+    /// Read syscalls counter
+    pub syscr: u64,
+    /// Write syscalls counter
+    pub syscw: u64,
+}
+
+/// Returns the breakdown of the process memory.
+///
+/// Only implemented on Linux and Android, where it is read from
+/// `/proc/self/smaps_rollup` and `/proc/self/status`: returns None on other
+/// systems.
+#[allow(unreachable_code)]
+pub fn get_memory_readings() -> ReadingsResult<Option<MemoryReadings>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return linux::get_memory_readings().map(Some);
+    Ok(None)
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryReadings {
+    /// Proportional set size: resident size, with shared pages divided
+    /// among the processes sharing them
+    pub pss: u64,
+    /// Resident anonymous memory (heap, stacks...)
+    pub anonymous: u64,
+    /// Resident file-backed memory (mapped files, binaries)
+    pub file: u64,
+    /// Resident shared memory
+    pub shmem: u64,
+    /// Swapped out memory
+    pub swap: u64,
+    /// Memory locked in RAM
+    pub locked: u64,
+}
+
+/// Returns the limits and usage of the cgroup of the process.
+///
+/// Only implemented on Linux and Android, for the cgroup v2 hierarchy or
+/// the v1 memory and cpu controllers: returns None on other systems, or
+/// when none of these controllers is mounted.
+#[allow(unreachable_code)]
+pub fn get_cgroup_readings() -> ReadingsResult<Option<CgroupReadings>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return linux::get_cgroup_readings();
+    Ok(None)
+}
+
+#[derive(Debug, Default)]
+pub struct CgroupReadings {
+    /// Memory limit, None if unlimited
+    pub memory_max: Option<u64>,
+    /// Memory charged to the cgroup, including the page cache
+    pub memory_current: u64,
+    /// CPU bandwidth limit (in cores), None if unlimited
+    pub cpu_max: Option<f64>,
+    /// Scheduling periods in which the cgroup was throttled
+    pub nr_throttled: u64,
+    /// Time spent throttled
+    pub throttled_time: Duration,
+}
+
+/// Returns the pressure stall information of the process cgroup.
+///
+/// Only implemented on Linux and Android, where it is read from the cgroup v2
+/// `*.pressure` files, or system-wide from `/proc/pressure`: returns None on
+/// other systems, or when the kernel does not track pressure.
+#[allow(unreachable_code)]
+pub fn get_pressure_readings() -> ReadingsResult<Option<PressureReadings>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return linux::get_pressure_readings();
+    Ok(None)
+}
+
+#[derive(Debug, Default)]
+pub struct PressureReadings {
+    /// Stalls waiting for a CPU
+    pub cpu: Pressure,
+    /// Stalls waiting for memory (reclaim, swap in, thrashing)
+    pub memory: Pressure,
+    /// Stalls waiting for I/O
+    pub io: Pressure,
+}
+
+#[derive(Debug, Default)]
+pub struct Pressure {
+    /// Share of the last 10 seconds some tasks were stalled (in %)
+    pub some_avg10: f64,
+    /// Share of the last 10 seconds all tasks were stalled (in %)
+    pub full_avg10: f64,
+    /// Time some tasks were stalled
+    pub some_total: Duration,
+    /// Time all tasks were stalled
+    pub full_total: Duration,
+}
+
+/// Returns the CPU times of each thread of the process.
+///
+/// Only implemented on Linux and Android: returns no threads on other
+/// systems.
+#[allow(unreachable_code)]
+pub fn get_thread_readings() -> ReadingsResult<Vec<ThreadReadings>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    return linux::get_thread_readings();
+    Ok(vec![])
+}
+
+#[derive(Debug)]
+pub struct ThreadReadings {
+    /// Thread id
+    pub tid: u64,
+    /// Thread name
+    pub name: String,
+    /// CPU Time in userland (in s)
+    pub user_time: Duration,
+    /// CPU Time in kernel (in s)
+    pub system_time: Duration,
+}
+
 #[derive(Debug)]
 pub struct OsReadings {
     /// Process virtual size
@@ -110,128 +297,362 @@ pub struct OsReadings {
     pub minor_fault: u64,
     /// Minor faults counter
     pub major_fault: u64,
+    /// Voluntary context switches counter
+    pub voluntary_context_switch: u64,
+    /// Involuntary context switches counter
+    pub involuntary_context_switch: u64,
+    /// Live threads (Linux only)
+    pub threads: u64,
+    /// Open file descriptors (Unix only)
+    pub open_fds: u64,
+}
+
+#[cfg(unix)]
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    Some(String::from_utf8_lossy(&buf[..len]).to_string())
+}
+
+#[cfg(not(unix))]
+fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME").ok()
+}
+
+/// Memory page size, as reported by the OS.
+#[cfg(unix)]
+pub(crate) fn page_size() -> Option<u64> {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 {
+        Some(size as u64)
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+pub(crate) fn page_size() -> Option<u64> {
+    None
 }
 
 /// The interface to reading probe.
+///
+/// Logging an event samples the vitals on the calling thread and hands the
+/// sample over to a dedicated writer thread through a bounded lock-free
+/// queue: it never waits on I/O. If the writer lags too far behind, samples
+/// are dropped and their count is recorded in the output.
 #[derive(Clone)]
-pub struct Probe(sync::Arc<sync::Mutex<ProbeData>>);
+pub struct Probe(sync::Arc<ProbeData>);
+
+/// Default capacity of the sample queue.
+pub(crate) const DEFAULT_BUFFER: usize = 1024;
 
 struct ProbeData {
     cores: usize,
-    origin: Option<std::time::Instant>,
-    writer: io::BufWriter<Box<dyn io::Write + Send>>,
-    metrics_i64: Vec<(String, Arc<AtomicI64>)>,
+    sources: Vec<Box<dyn ReadingSource>>,
+    /// Number of columns of each source.
+    widths: Vec<usize>,
+    origin: time::Instant,
+    start_time: time::SystemTime,
+    metadata: Vec<(String, String)>,
+    metrics: Vec<metric::Registered>,
+    thread_cpu: bool,
+    started: sync::Once,
+    writer: writer::Writer,
+    heartbeats: sync::Mutex<Vec<Arc<heartbeat::HeartbeatControl>>>,
+    stopped: AtomicBool,
+    finished: AtomicBool,
 }
 
 impl ProbeData {
-    fn write_line(&mut self, now: time::Instant, reason: &str) -> ReadingsResult<()> {
-        if self.origin.is_none() {
-            write!(self.writer, "   time cor        vsz        rsz     rszmax")?;
-            write!(self.writer, "    utime    stime       minf       majf")?;
-            write!(self.writer, "      alloc       free")?;
-            for m in &self.metrics_i64 {
-                write!(self.writer, " {:>10}", m.0)?;
+    fn new(
+        writer: Box<dyn io::Write + Send>,
+        encoder: format::Encoder,
+        buffer: usize,
+        sources: Vec<Box<dyn ReadingSource>>,
+    ) -> ProbeData {
+        let widths = sources.iter().map(|s| s.columns().len()).collect();
+        let origin = time::Instant::now();
+        ProbeData {
+            cores: num_cpus::get(),
+            sources,
+            widths,
+            origin,
+            start_time: time::SystemTime::now(),
+            metadata: vec![],
+            metrics: vec![],
+            thread_cpu: false,
+            started: sync::Once::new(),
+            writer: writer::Writer::spawn(writer, encoder, buffer, origin),
+            heartbeats: sync::Mutex::new(vec![]),
+            stopped: AtomicBool::new(false),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    fn header(&self) -> Header {
+        let mut metadata = vec![];
+        // arguments are not necessarily valid UTF-8
+        let mut args = std::env::args_os();
+        if let Some(program) = args.next() {
+            let program = std::path::Path::new(&program)
+                .file_name()
+                .unwrap_or(&program)
+                .to_string_lossy()
+                .to_string();
+            metadata.push(("program".to_string(), program));
+        }
+        metadata.extend(args.map(|arg| ("arg".to_string(), arg.to_string_lossy().to_string())));
+        metadata.push(("pid".to_string(), std::process::id().to_string()));
+        if let Some(hostname) = hostname() {
+            metadata.push(("hostname".to_string(), hostname));
+        }
+        if let Ok(start) = self.start_time.duration_since(time::UNIX_EPOCH) {
+            metadata.push((
+                "start_time".to_string(),
+                format!("{:.6}", start.as_secs_f64()),
+            ));
+        }
+        if let Some(page_size) = page_size() {
+            metadata.push(("page_size".to_string(), page_size.to_string()));
+        }
+        metadata.push(("version".to_string(), env!("CARGO_PKG_VERSION").to_string()));
+        for source in &self.sources {
+            metadata.extend(source.metadata());
+        }
+        metadata.extend(self.metadata.iter().cloned());
+
+        let mut columns = vec![
+            Column::new("time", ValueType::F64, "s", Kind::Gauge, "probe"),
+            Column::new("cores", ValueType::U64, "-", Kind::Gauge, "probe"),
+        ];
+        for source in &self.sources {
+            columns.extend(source.columns());
+        }
+        for m in &self.metrics {
+            columns.extend(m.metric.columns(&m.name, &m.unit));
+        }
+        Header { metadata, columns }
+    }
+
+    /// Queues the header, once. The metric set is frozen from there on.
+    fn start(&self) {
+        self.started
+            .call_once(|| self.writer.push(writer::Message::Header(self.header())));
+    }
+
+    /// Samples all the columns, timestamped when the sampling starts.
+    fn sample(&self, reason: &str, fields: &[(&str, FieldValue)]) -> ReadingsResult<Row> {
+        let time = self.origin.elapsed().as_secs_f64();
+        let mut values = vec![Value::F64(time), Value::U64(self.cores as u64)];
+        for (source, &width) in self.sources.iter().zip(&self.widths) {
+            let before = values.len();
+            source.sample(&mut values)?;
+            let got = values.len() - before;
+            if got != width {
+                return Err(ReadingsError::SourceValues {
+                    expected: width,
+                    got,
+                });
             }
-            writeln!(self.writer, " event")?;
-            self.origin = Some(now)
         }
-        let usage = get_os_readings()?;
-        write!(
-            self.writer,
-            "{:7.3} {:3}",
-            (now - self.origin.unwrap()).as_secs_f32(),
-            self.cores
-        )?;
-        write!(
-            self.writer,
-            " {:10} {:10} {:10}",
-            usage.virtual_size, usage.resident_size, usage.resident_size_max
-        )?;
-        write!(
-            self.writer,
-            " {:8.6} {:8.6} {:10} {:10}",
-            usage.user_time.as_secs_f64(),
-            usage.system_time.as_secs_f64(),
-            usage.minor_fault,
-            usage.major_fault
-        )?;
-        write!(
-            self.writer,
-            " {:10} {:10}",
-            alloc::ALLOCATED.load(Relaxed),
-            alloc::FREEED.load(Relaxed)
-        )?;
-        for m in &self.metrics_i64 {
-            write!(self.writer, " {:10}", m.1.load(Relaxed),)?;
+        let mut fields: Vec<(String, FieldValue)> = fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        for m in &self.metrics {
+            // a panicking gauge callback is flagged on the line, with its
+            // value set to zero
+            if let Err(message) = m.metric.push_values(&mut values) {
+                fields.push((
+                    "error".to_string(),
+                    FieldValue::from(format!("{} panicked: {}", m.name, message)),
+                ));
+            }
+        }
+        let event = if reason.is_empty() {
+            None
+        } else {
+            Some(reason.to_string())
+        };
+        Ok(Row {
+            values,
+            event,
+            fields,
+            span: None,
+        })
+    }
+
+    fn write_line(&self, reason: &str, fields: &[(&str, FieldValue)]) -> ReadingsResult<()> {
+        self.start();
+        let _sampling = self.writer.sampling();
+        let row = self.sample(reason, fields)?;
+        self.writer.push(writer::Message::Row(row));
+        Ok(())
+    }
+
+    /// Writes a heartbeat line, and the per-thread CPU times if enabled.
+    fn heartbeat(&self) -> ReadingsResult<()> {
+        self.write_line("", &[])?;
+        if self.thread_cpu {
+            let _sampling = self.writer.sampling();
+            let time = self.origin.elapsed().as_secs_f64();
+            let threads = get_thread_readings()?
+                .into_iter()
+                .map(|t| ThreadCpu {
+                    tid: t.tid,
+                    name: t.name,
+                    user_time: t.user_time.as_secs_f64(),
+                    system_time: t.system_time.as_secs_f64(),
+                })
+                .collect();
+            self.writer
+                .push(writer::Message::Threads(ThreadSample { time, threads }));
         }
-        writeln!(self.writer, " {}", reason)?;
-        self.writer.flush()?;
         Ok(())
     }
+
+    fn write_span(&self, name: &str, edge: SpanEdge) -> ReadingsResult<()> {
+        self.start();
+        let _sampling = self.writer.sampling();
+        let mut row = self.sample(name, &[])?;
+        row.span = Some(edge);
+        self.writer.push(writer::Message::Row(row));
+        Ok(())
+    }
+
+    fn write_stopped(&self) -> ReadingsResult<()> {
+        if !self.stopped.swap(true, Relaxed) {
+            self.write_line("stopped", &[])?;
+        }
+        Ok(())
+    }
+
+    /// Stops the heartbeats, writes the "stopped" line and waits for the
+    /// writer thread to write out everything.
+    fn finish(&self) -> ReadingsResult<()> {
+        if self.finished.swap(true, Relaxed) {
+            return Ok(());
+        }
+        if let Ok(mut heartbeats) = self.heartbeats.lock() {
+            for hb in heartbeats.drain(..) {
+                hb.stop();
+            }
+        }
+        let stopped = self.write_stopped();
+        self.writer.close()?;
+        stopped
+    }
+}
+
+impl Drop for ProbeData {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("{:?}", e);
+        }
+    }
 }
 
 impl Probe {
     /// Creates a probe logging its data to Write implementation (usually a
     /// file).
+    ///
+    /// Prefer `ProbeBuilder` when user-defined metrics are needed.
     pub fn new<W: Write + Send + 'static>(write: W) -> ReadingsResult<Probe> {
-        let mut writer = io::BufWriter::new(Box::new(write) as _);
-        writeln!(writer, "#ReadingsV1")?;
-        let data = ProbeData {
-            cores: num_cpus::get(),
-            origin: None,
-            writer,
-            metrics_i64: vec![],
-        };
-        Ok(Probe(sync::Arc::new(sync::Mutex::new(data))))
+        Ok(Probe::from_data(ProbeData::new(
+            Box::new(write),
+            format::Encoder::new(format::Encoding::Text),
+            DEFAULT_BUFFER,
+            source::default_sources(),
+        )))
+    }
+
+    fn from_data(data: ProbeData) -> Probe {
+        Probe(sync::Arc::new(data))
     }
 
     /// Register an i64 used-defined metric.
     ///
-    /// Must be called prior to the first call to `log_event` or `spawn_heartbeat`.
+    /// Must be called prior to the first call to `log_event` or
+    /// `spawn_heartbeat`, and before the probe is cloned.
     ///
     /// The result is shared AtomicI64 that can be used by client code to share
     /// communicate updates with the probe.
-    ///
-    /// TODO: type-enforce this using the Builder pattern
+    #[deprecated(note = "register metrics on a ProbeBuilder instead")]
     pub fn register_i64<S: AsRef<str>>(&mut self, name: S) -> ReadingsResult<Arc<AtomicI64>> {
-        let mut m = self.0.lock().map_err(|_| ReadingsError::PoisonedProbe)?;
-        if m.origin.is_some() {
-            return Err(ReadingsError::LateRegistertingMetricsAttempt);
-        }
+        let m = match sync::Arc::get_mut(&mut self.0) {
+            Some(m) if !m.started.is_completed() => m,
+            _ => return Err(ReadingsError::LateRegistertingMetricsAttempt),
+        };
         let it = Arc::new(AtomicI64::new(0));
-        m.metrics_i64
-            .push((name.as_ref().replace(" ", "_"), it.clone()));
+        m.metrics.push(metric::Registered::new(
+            name.as_ref(),
+            Metric::I64(it.clone()),
+        ));
         Ok(it)
     }
 
     /// Spawn a thread that will record all vitals at every "interval".
-    pub fn spawn_heartbeat(&mut self, interval: time::Duration) -> ReadingsResult<()> {
-        let probe = self.clone();
-        probe.log_event("spawned_heartbeat")?;
-        let origin = probe
-            .0
+    ///
+    /// The returned handle can be used to stop the heartbeat. It is also
+    /// stopped by `finish()` or when the last clone of the probe is dropped.
+    pub fn spawn_heartbeat(&mut self, interval: time::Duration) -> ReadingsResult<HeartbeatHandle> {
+        self.log_event("spawned_heartbeat")?;
+        let control = Arc::new(heartbeat::HeartbeatControl::default());
+        self.0
+            .heartbeats
             .lock()
             .map_err(|_| ReadingsError::PoisonedProbe)?
-            .origin
-            .unwrap();
-        std::thread::spawn(move || {
-            for step in 1.. {
-                let wanted = origin + (step * interval);
-                let now = std::time::Instant::now();
-                if wanted > now {
-                    ::std::thread::sleep(wanted - now);
-                }
-                if let Err(e) = probe.log_event("") {
-                    eprintln!("{:?}", e);
-                }
-            }
-        });
-        Ok(())
+            .push(control.clone());
+        Ok(HeartbeatHandle::spawn(
+            Arc::downgrade(&self.0),
+            time::Instant::now(),
+            interval,
+            control,
+        ))
+    }
+
+    /// Stops the heartbeats, writes a last "stopped" line and waits for
+    /// everything to be written and flushed to the underlying writer.
+    ///
+    /// Events logged afterwards, from other clones of the probe, are ignored.
+    /// This also happens automatically when the last clone of the probe is
+    /// dropped.
+    pub fn finish(self) -> ReadingsResult<()> {
+        self.0.finish()
     }
 
     /// Log an individual event with a label and the current values of metrics.
+    ///
+    /// This does not block on I/O: the sample is queued for the writer thread.
     pub fn log_event(&self, event: &str) -> ReadingsResult<()> {
-        self.write_line(std::time::Instant::now(), &event.replace(" ", "_"))
+        self.write_line(event, &[])
+    }
+
+    /// Log an individual event with a label, typed key/value fields and the
+    /// current values of metrics.
+    ///
+    /// ```rust
+    /// fn main() -> readings_probe::ReadingsResult<()> {
+    ///     let probe = readings_probe::ProbeBuilder::new(std::io::sink()).build()?;
+    ///     probe.log_event_with("batch done", &[("size", 128.into()), ("file", "input.csv".into())])?;
+    ///     Ok(())
+    /// }
+    /// ```
+    pub fn log_event_with(&self, event: &str, fields: &[(&str, FieldValue)]) -> ReadingsResult<()> {
+        self.write_line(event, fields)
+    }
+
+    /// Opens a span, ended when the returned guard is dropped.
+    ///
+    /// Begin and end lines are logged with the current values of metrics,
+    /// like events. Spans opened on the same thread while the guard is alive
+    /// are recorded as its children.
+    pub fn span(&self, name: &str) -> ReadingsResult<SpanGuard> {
+        SpanGuard::begin(self, name)
     }
 
     /// Recover a pre-registered used-defined metrics from the probe.
@@ -244,19 +665,65 @@ impl Probe {
     /// intermediate code to just have to propagate the probe without worrying
     /// about the various metrics that the underlying code may need.
     pub fn get_i64<S: AsRef<str>>(&self, name: S) -> Option<Arc<AtomicI64>> {
-        let name = name.as_ref().replace(" ", "_");
-        self.0.lock().ok().and_then(|l| {
-            l.metrics_i64
-                .iter()
-                .find(|m| (m.0 == name))
-                .map(|m| m.1.clone())
-        })
+        match self.get_metric(name) {
+            Some(Metric::I64(it)) => Some(it),
+            _ => None,
+        }
     }
 
-    fn write_line(&self, now: time::Instant, reason: &str) -> ReadingsResult<()> {
+    /// Recover a pre-registered u64 or counter used-defined metrics from the probe.
+    ///
+    /// See `get_i64`.
+    pub fn get_u64<S: AsRef<str>>(&self, name: S) -> Option<Arc<AtomicU64>> {
+        match self.get_metric(name) {
+            Some(Metric::U64(it)) | Some(Metric::Counter(it)) => Some(it),
+            _ => None,
+        }
+    }
+
+    /// Recover a pre-registered f64 used-defined metrics from the probe.
+    ///
+    /// See `get_i64`.
+    pub fn get_f64<S: AsRef<str>>(&self, name: S) -> Option<Arc<AtomicF64>> {
+        match self.get_metric(name) {
+            Some(Metric::F64(it)) => Some(it),
+            _ => None,
+        }
+    }
+
+    /// Recover a pre-registered histogram from the probe.
+    ///
+    /// See `get_i64`.
+    pub fn get_histogram<S: AsRef<str>>(&self, name: S) -> Option<Arc<Histogram>> {
+        match self.get_metric(name) {
+            Some(Metric::Histogram(it)) => Some(it),
+            _ => None,
+        }
+    }
+
+    /// Recover a pre-registered used-defined metrics of any type from the probe.
+    pub fn get_metric<S: AsRef<str>>(&self, name: S) -> Option<Metric> {
+        let name = name.as_ref();
         self.0
-            .lock()
-            .map_err(|_| ReadingsError::PoisonedProbe)?
-            .write_line(now, reason)
+            .metrics
+            .iter()
+            .find(|m| m.name == name)
+            .map(|m| m.metric.clone())
+    }
+
+    fn write_line(&self, reason: &str, fields: &[(&str, FieldValue)]) -> ReadingsResult<()> {
+        self.0.write_line(reason, fields)
+    }
+
+    fn write_span(&self, name: &str, edge: SpanEdge) -> ReadingsResult<()> {
+        self.0.write_span(name, edge)
+    }
+
+    fn heartbeat(&self) -> ReadingsResult<()> {
+        self.0.heartbeat()
+    }
+
+    fn write_stopped(&self) -> ReadingsResult<()> {
+        self.0.write_stopped()
     }
 }
@@ -1,8 +1,12 @@
 use libc::{getrusage, rusage, RUSAGE_SELF};
 
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
-use super::{OsReadings, ReadingsError};
+use super::{
+    page_size, CgroupReadings, IoReadings, MemoryReadings, OsReadings, Pressure, PressureReadings,
+    ReadingsError, ThreadReadings,
+};
 
 fn get_rusage() -> rusage {
     unsafe {
@@ -12,18 +16,607 @@ fn get_rusage() -> rusage {
     }
 }
 
+/// The fields we use from a `/proc/<pid>/stat` or `/proc/<pid>/task/<tid>/stat`
+/// file.
+#[derive(Debug, PartialEq)]
+struct Stat {
+    comm: String,
+    /// CPU time in userland, in clock ticks
+    utime: u64,
+    /// CPU time in kernel, in clock ticks
+    stime: u64,
+    num_threads: u64,
+    /// Virtual size, in bytes
+    vsize: u64,
+    /// Resident size, in pages
+    rss: u64,
+}
+
+/// Parses a stat file.
+///
+/// The command name, second field, is between parentheses and may contain
+/// spaces and parentheses itself: the other fields are found after the last
+/// closing parenthesis.
+fn parse_stat(content: &str) -> Result<Stat, ReadingsError> {
+    let (open, close) = match (content.find('('), content.rfind(')')) {
+        (Some(open), Some(close)) if open < close => (open, close),
+        _ => {
+            return Err(ReadingsError::MalformedStat(
+                "no command name between parentheses".to_string(),
+            ))
+        }
+    };
+    let fields: Vec<&str> = content[close + 1..].split_whitespace().collect();
+    // fields are numbered from 1 in proc(5), the first one after the command
+    // name being the state, field 3
+    let field = |number: usize| -> Result<u64, ReadingsError> {
+        let value = fields
+            .get(number - 3)
+            .ok_or_else(|| ReadingsError::MalformedStat(format!("missing field {}", number)))?;
+        value.parse().map_err(|_| ReadingsError::InvalidStatField {
+            field: number,
+            value: value.to_string(),
+        })
+    };
+    Ok(Stat {
+        comm: content[open + 1..close].to_string(),
+        utime: field(14)?,
+        stime: field(15)?,
+        num_threads: field(20)?,
+        vsize: field(23)?,
+        rss: field(24)?,
+    })
+}
+
 pub(crate) fn get_os_readings() -> Result<OsReadings, ReadingsError> {
-    let proc_stat =
-        std::fs::read_to_string("/proc/self/stat").map_err(|e| ReadingsError::ProcStat(e))?;
-    let mut tokens = proc_stat.split(" ");
+    let proc_stat = std::fs::read_to_string("/proc/self/stat").map_err(ReadingsError::ProcStat)?;
+    let stat = parse_stat(&proc_stat)?;
     let rusage = get_rusage();
+    // the directory listing holds a descriptor of its own
+    let open_fds = std::fs::read_dir("/proc/self/fd")
+        .map(|fds| fds.count().saturating_sub(1))
+        .unwrap_or(0);
     Ok(OsReadings {
-        virtual_size: tokens.nth(22).unwrap().parse().unwrap_or(0),
-        resident_size: 4 * 1024 * tokens.next().unwrap().parse().unwrap_or(0),
+        virtual_size: stat.vsize,
+        resident_size: page_size().unwrap_or(4096) * stat.rss,
         resident_size_max: 1024 * rusage.ru_maxrss as u64,
-        user_time: Duration::from_secs(rusage.ru_utime.tv_sec as _) + Duration::from_micros(rusage.ru_utime.tv_usec as _),
-        system_time: Duration::from_secs(rusage.ru_stime.tv_sec as _) + Duration::from_micros(rusage.ru_stime.tv_usec as _),
+        user_time: Duration::from_secs(rusage.ru_utime.tv_sec as _)
+            + Duration::from_micros(rusage.ru_utime.tv_usec as _),
+        system_time: Duration::from_secs(rusage.ru_stime.tv_sec as _)
+            + Duration::from_micros(rusage.ru_stime.tv_usec as _),
         minor_fault: rusage.ru_minflt as u64,
         major_fault: rusage.ru_majflt as u64,
+        voluntary_context_switch: rusage.ru_nvcsw as u64,
+        involuntary_context_switch: rusage.ru_nivcsw as u64,
+        threads: stat.num_threads,
+        open_fds: open_fds as u64,
+    })
+}
+
+pub(crate) fn get_io_readings() -> Result<IoReadings, ReadingsError> {
+    let proc_io = std::fs::read_to_string("/proc/self/io").map_err(ReadingsError::ProcIo)?;
+    let mut io = IoReadings::default();
+    for line in proc_io.lines() {
+        let mut tokens = line.split(':');
+        let field = match tokens.next() {
+            Some("rchar") => &mut io.rchar,
+            Some("wchar") => &mut io.wchar,
+            Some("read_bytes") => &mut io.read_bytes,
+            Some("write_bytes") => &mut io.write_bytes,
+            Some("syscr") => &mut io.syscr,
+            Some("syscw") => &mut io.syscw,
+            _ => continue,
+        };
+        *field = tokens.next().and_then(|v| v.trim().parse().ok()).unwrap_or(0);
+    }
+    Ok(io)
+}
+
+/// Looks up a "Key:   123 kB" line, as found in status and smaps files, in
+/// bytes.
+fn kb_field(content: &str, key: &str) -> Option<u64> {
+    content.lines().find_map(|line| {
+        let value = line.strip_prefix(key)?.strip_prefix(':')?;
+        let kb: u64 = value.trim().trim_end_matches("kB").trim().parse().ok()?;
+        Some(kb * 1024)
+    })
+}
+
+pub(crate) fn get_memory_readings() -> Result<MemoryReadings, ReadingsError> {
+    let status = std::fs::read_to_string("/proc/self/status").map_err(ReadingsError::ProcStatus)?;
+    // smaps_rollup appeared in Linux 4.14
+    let rollup = std::fs::read_to_string("/proc/self/smaps_rollup").unwrap_or_default();
+    Ok(MemoryReadings {
+        pss: kb_field(&rollup, "Pss").unwrap_or(0),
+        anonymous: kb_field(&status, "RssAnon").unwrap_or(0),
+        file: kb_field(&status, "RssFile").unwrap_or(0),
+        shmem: kb_field(&status, "RssShmem").unwrap_or(0),
+        swap: kb_field(&status, "VmSwap").unwrap_or(0),
+        locked: kb_field(&status, "VmLck").unwrap_or(0),
+    })
+}
+
+/// Directory of a cgroup controller, and the version of its hierarchy.
+#[derive(Debug, PartialEq)]
+enum Cgroup {
+    V1(PathBuf),
+    V2(PathBuf),
+}
+
+/// Directory of a cgroup under the hierarchy mount point.
+///
+/// Without a cgroup namespace, a container sees the path of its cgroup on
+/// the host, but has its own cgroup mounted at the root.
+fn cgroup_dir(mount: &Path, path: &str) -> PathBuf {
+    let dir = mount.join(path.trim_start_matches('/'));
+    if dir.is_dir() {
+        dir
+    } else {
+        mount.to_path_buf()
+    }
+}
+
+/// Mount point of the cgroup v2 hierarchy, which sits besides the v1
+/// controllers on hybrid systems.
+fn unified_mount(root: &Path) -> PathBuf {
+    let unified = root.join("unified");
+    if unified.is_dir() {
+        unified
+    } else {
+        root.to_path_buf()
+    }
+}
+
+/// The memory and cpu controllers of the process cgroup.
+pub(crate) struct CgroupControllers {
+    memory: Option<Cgroup>,
+    cpu: Option<Cgroup>,
+}
+
+impl CgroupControllers {
+    /// Finds the controllers, or returns None if neither is mounted.
+    pub(crate) fn find() -> Result<Option<CgroupControllers>, ReadingsError> {
+        let proc_cgroup =
+            std::fs::read_to_string("/proc/self/cgroup").map_err(ReadingsError::ProcCgroup)?;
+        Ok(CgroupControllers::parse(
+            &proc_cgroup,
+            Path::new("/sys/fs/cgroup"),
+        ))
+    }
+
+    /// Resolves the controllers listed in /proc/self/cgroup under the
+    /// cgroup filesystem root.
+    fn parse(proc_cgroup: &str, root: &Path) -> Option<CgroupControllers> {
+        let (mut memory, mut cpu) = (None, None);
+        // lines are "hierarchy-id:controllers:path", with no controllers for v2
+        for line in proc_cgroup.lines() {
+            let mut tokens = line.splitn(3, ':');
+            let (controllers, path) = match (tokens.next(), tokens.next(), tokens.next()) {
+                (Some(_), Some(controllers), Some(path)) => (controllers, path),
+                _ => continue,
+            };
+            if controllers.is_empty() {
+                let dir = cgroup_dir(&unified_mount(root), path);
+                let available = std::fs::read_to_string(dir.join("cgroup.controllers"));
+                for controller in available.unwrap_or_default().split_whitespace() {
+                    match controller {
+                        "memory" if memory.is_none() => memory = Some(Cgroup::V2(dir.clone())),
+                        "cpu" if cpu.is_none() => cpu = Some(Cgroup::V2(dir.clone())),
+                        _ => (),
+                    }
+                }
+            } else {
+                // co-mounted controllers are usually symlinked under each name
+                let mount = |name: &str| {
+                    let mount = root.join(controllers);
+                    let mount = if mount.is_dir() {
+                        mount
+                    } else {
+                        root.join(name)
+                    };
+                    Cgroup::V1(cgroup_dir(&mount, path))
+                };
+                for controller in controllers.split(',') {
+                    match controller {
+                        "memory" => memory = Some(mount("memory")),
+                        "cpu" => cpu = Some(mount("cpu")),
+                        _ => (),
+                    }
+                }
+            }
+        }
+        if memory.is_none() && cpu.is_none() {
+            return None;
+        }
+        Some(CgroupControllers { memory, cpu })
+    }
+}
+
+fn read_cgroup_file(dir: &Path, name: &str) -> Option<String> {
+    std::fs::read_to_string(dir.join(name))
+        .ok()
+        .map(|s| s.trim().to_string())
+}
+
+/// Looks up a "key value" line, as found in cgroup stat files.
+fn stat_field(content: &str, key: &str) -> Option<u64> {
+    content.lines().find_map(|line| {
+        let mut tokens = line.split_whitespace();
+        if tokens.next()? != key {
+            return None;
+        }
+        tokens.next()?.parse().ok()
     })
 }
+
+impl CgroupControllers {
+    /// Reads the memory and CPU limits.
+    pub(crate) fn limits(&self, cgroup: &mut CgroupReadings) {
+        match &self.memory {
+            // "max" when unlimited
+            Some(Cgroup::V2(dir)) => {
+                cgroup.memory_max =
+                    read_cgroup_file(dir, "memory.max").and_then(|s| s.parse().ok());
+            }
+            // unlimited is reported as the largest page-aligned i64
+            Some(Cgroup::V1(dir)) => {
+                cgroup.memory_max = read_cgroup_file(dir, "memory.limit_in_bytes")
+                    .and_then(|s| s.parse().ok())
+                    .filter(|&max: &u64| max < 1 << 62);
+            }
+            None => (),
+        }
+        match &self.cpu {
+            Some(Cgroup::V2(dir)) => {
+                // "<quota> <period>" in microseconds, quota is "max" when unlimited
+                if let Some(max) = read_cgroup_file(dir, "cpu.max") {
+                    let mut tokens = max.split_whitespace();
+                    if let (Some(Ok(quota)), Some(Ok(period))) = (
+                        tokens.next().map(str::parse::<f64>),
+                        tokens.next().map(str::parse::<f64>),
+                    ) {
+                        cgroup.cpu_max = Some(quota / period).filter(|max| max.is_finite());
+                    }
+                }
+            }
+            Some(Cgroup::V1(dir)) => {
+                // quota is -1 when unlimited
+                let quota = read_cgroup_file(dir, "cpu.cfs_quota_us").and_then(|s| s.parse().ok());
+                let period =
+                    read_cgroup_file(dir, "cpu.cfs_period_us").and_then(|s| s.parse().ok());
+                if let (Some(quota), Some(period)) = (quota, period) {
+                    let (quota, period): (f64, f64) = (quota, period);
+                    if quota > 0.0 && period > 0.0 {
+                        cgroup.cpu_max = Some(quota / period);
+                    }
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Reads the memory usage and the throttling counters.
+    pub(crate) fn usage(&self, cgroup: &mut CgroupReadings) {
+        let current = match &self.memory {
+            Some(Cgroup::V2(dir)) => read_cgroup_file(dir, "memory.current"),
+            Some(Cgroup::V1(dir)) => read_cgroup_file(dir, "memory.usage_in_bytes"),
+            None => None,
+        };
+        cgroup.memory_current = current.and_then(|s| s.parse().ok()).unwrap_or(0);
+        match &self.cpu {
+            Some(Cgroup::V2(dir)) => {
+                let stat = read_cgroup_file(dir, "cpu.stat").unwrap_or_default();
+                cgroup.nr_throttled = stat_field(&stat, "nr_throttled").unwrap_or(0);
+                cgroup.throttled_time =
+                    Duration::from_micros(stat_field(&stat, "throttled_usec").unwrap_or(0));
+            }
+            Some(Cgroup::V1(dir)) => {
+                let stat = read_cgroup_file(dir, "cpu.stat").unwrap_or_default();
+                cgroup.nr_throttled = stat_field(&stat, "nr_throttled").unwrap_or(0);
+                cgroup.throttled_time =
+                    Duration::from_nanos(stat_field(&stat, "throttled_time").unwrap_or(0));
+            }
+            None => (),
+        }
+    }
+}
+
+pub(crate) fn get_cgroup_readings() -> Result<Option<CgroupReadings>, ReadingsError> {
+    let controllers = match CgroupControllers::find()? {
+        Some(controllers) => controllers,
+        None => return Ok(None),
+    };
+    let mut cgroup = CgroupReadings::default();
+    controllers.limits(&mut cgroup);
+    controllers.usage(&mut cgroup);
+    Ok(Some(cgroup))
+}
+
+/// Parses a pressure file, made of "some" and "full" lines like
+/// "some avg10=0.12 avg60=0.02 avg300=0.00 total=5356100".
+fn parse_pressure(content: &str) -> Pressure {
+    let mut pressure = Pressure::default();
+    for line in content.lines() {
+        let mut tokens = line.split_whitespace();
+        let (avg10, total) = match tokens.next() {
+            Some("some") => (&mut pressure.some_avg10, &mut pressure.some_total),
+            Some("full") => (&mut pressure.full_avg10, &mut pressure.full_total),
+            _ => continue,
+        };
+        for token in tokens {
+            match token.split_once('=') {
+                Some(("avg10", v)) => *avg10 = v.parse().unwrap_or(0.0),
+                // in microseconds
+                Some(("total", v)) => *total = Duration::from_micros(v.parse().unwrap_or(0)),
+                _ => (),
+            }
+        }
+    }
+    pressure
+}
+
+/// Pressure files of the process cgroup, falling back on the system-wide
+/// ones.
+pub(crate) struct PressureFiles {
+    cpu: PathBuf,
+    memory: Option<PathBuf>,
+    io: Option<PathBuf>,
+}
+
+impl PressureFiles {
+    /// Finds the files, or returns None if pressure is not tracked.
+    pub(crate) fn find() -> Result<Option<PressureFiles>, ReadingsError> {
+        let proc_cgroup =
+            std::fs::read_to_string("/proc/self/cgroup").map_err(ReadingsError::ProcCgroup)?;
+        Ok(PressureFiles::parse(
+            &proc_cgroup,
+            Path::new("/sys/fs/cgroup"),
+        ))
+    }
+
+    /// Resolves the files of the v2 cgroup listed in /proc/self/cgroup under
+    /// the cgroup filesystem root.
+    fn parse(proc_cgroup: &str, root: &Path) -> Option<PressureFiles> {
+        let cgroup = proc_cgroup
+            .lines()
+            .find_map(|line| line.strip_prefix("0::"))
+            .map(|path| cgroup_dir(&unified_mount(root), path));
+        // the files are missing, or cannot be read when pressure tracking is disabled
+        let find = |resource: &str| {
+            let name = format!("{}.pressure", resource);
+            cgroup
+                .iter()
+                .map(|dir| dir.join(&name))
+                .chain(Some(Path::new("/proc/pressure").join(resource)))
+                .find(|file| std::fs::read_to_string(file).is_ok())
+        };
+        Some(PressureFiles {
+            cpu: find("cpu")?,
+            memory: find("memory"),
+            io: find("io"),
+        })
+    }
+
+    pub(crate) fn read(&self) -> PressureReadings {
+        let read = |file: Option<&PathBuf>| {
+            let content = file.and_then(|f| std::fs::read_to_string(f).ok());
+            parse_pressure(&content.unwrap_or_default())
+        };
+        PressureReadings {
+            cpu: read(Some(&self.cpu)),
+            memory: read(self.memory.as_ref()),
+            io: read(self.io.as_ref()),
+        }
+    }
+}
+
+pub(crate) fn get_pressure_readings() -> Result<Option<PressureReadings>, ReadingsError> {
+    Ok(PressureFiles::find()?.map(|files| files.read()))
+}
+
+/// Clock ticks per second, the unit of CPU times in /proc stat files.
+fn clock_ticks() -> f64 {
+    match unsafe { libc::sysconf(libc::_SC_CLK_TCK) } {
+        ticks if ticks > 0 => ticks as f64,
+        _ => 100.0,
+    }
+}
+
+pub(crate) fn get_thread_readings() -> Result<Vec<ThreadReadings>, ReadingsError> {
+    let ticks = clock_ticks();
+    let mut threads = vec![];
+    for entry in std::fs::read_dir("/proc/self/task").map_err(ReadingsError::ProcTask)? {
+        let entry = entry.map_err(ReadingsError::ProcTask)?;
+        let tid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        // threads may terminate while we walk the directory
+        let stat = match std::fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        let stat = parse_stat(&stat)?;
+        let time = |ticks_used: u64| Duration::from_secs_f64(ticks_used as f64 / ticks);
+        threads.push(ThreadReadings {
+            tid,
+            name: stat.comm,
+            user_time: time(stat.utime),
+            system_time: time(stat.stime),
+        });
+    }
+    threads.sort_by_key(|t| t.tid);
+    Ok(threads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stat() {
+        let stat = parse_stat(include_str!("../tests/fixtures/stat")).unwrap();
+        assert_eq!(
+            stat,
+            Stat {
+                comm: "cat".to_string(),
+                utime: 7,
+                stime: 3,
+                num_threads: 1,
+                vsize: 2703360,
+                rss: 306,
+            }
+        );
+    }
+
+    #[test]
+    fn stat_with_parens_in_comm() {
+        let stat = parse_stat(include_str!("../tests/fixtures/stat_parens")).unwrap();
+        assert_eq!(stat.comm, "tokio (worker) 1");
+        assert_eq!((stat.utime, stat.stime), (1520, 340));
+        assert_eq!(stat.num_threads, 9);
+        assert_eq!((stat.vsize, stat.rss), (1103265792, 20480));
+    }
+
+    #[test]
+    fn stat_truncated() {
+        match parse_stat(include_str!("../tests/fixtures/stat_truncated")) {
+            Err(ReadingsError::MalformedStat(_)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn stat_invalid_field() {
+        match parse_stat(include_str!("../tests/fixtures/stat_invalid")) {
+            Err(ReadingsError::InvalidStatField { field: 24, value }) => assert_eq!(value, "--"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn stat_without_comm() {
+        assert!(parse_stat("").is_err());
+        assert!(parse_stat("4242 cat) R (").is_err());
+    }
+
+    #[test]
+    fn status() {
+        let status = include_str!("../tests/fixtures/status");
+        assert_eq!(kb_field(status, "RssAnon"), Some(100 * 1024));
+        assert_eq!(kb_field(status, "RssFile"), Some(1308 * 1024));
+        assert_eq!(kb_field(status, "VmLck"), Some(0));
+        assert_eq!(kb_field(status, "Rss"), None);
+    }
+
+    #[test]
+    fn pressure() {
+        let pressure = parse_pressure(include_str!("../tests/fixtures/pressure"));
+        assert_eq!(pressure.some_avg10, 8.46);
+        assert_eq!(pressure.some_total, Duration::from_micros(111914383));
+        assert_eq!(pressure.full_avg10, 0.5);
+        assert_eq!(pressure.full_total, Duration::from_micros(1250));
+    }
+
+    fn cgroup_root(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/cgroup")
+            .join(name)
+    }
+
+    fn cgroup_readings(controllers: &CgroupControllers) -> CgroupReadings {
+        let mut cgroup = CgroupReadings::default();
+        controllers.limits(&mut cgroup);
+        controllers.usage(&mut cgroup);
+        cgroup
+    }
+
+    #[test]
+    fn cgroup_v2() {
+        let root = cgroup_root("v2");
+        let controllers = CgroupControllers::parse("0::/app\n", &root).unwrap();
+        assert_eq!(controllers.memory, Some(Cgroup::V2(root.join("app"))));
+        assert_eq!(controllers.cpu, Some(Cgroup::V2(root.join("app"))));
+        let cgroup = cgroup_readings(&controllers);
+        assert_eq!(cgroup.memory_max, Some(536870912));
+        assert_eq!(cgroup.memory_current, 2097152);
+        assert_eq!(cgroup.cpu_max, Some(0.5));
+        assert_eq!(cgroup.nr_throttled, 3);
+        assert_eq!(cgroup.throttled_time, Duration::from_micros(1500));
+    }
+
+    #[test]
+    fn cgroup_v2_unlimited() {
+        let root = cgroup_root("v2");
+        let controllers = CgroupControllers::parse("0::/\n", &root).unwrap();
+        assert_eq!(controllers.memory, Some(Cgroup::V2(root.clone())));
+        let cgroup = cgroup_readings(&controllers);
+        // memory.max is "max", cpu.max is "max 100000"
+        assert_eq!(cgroup.memory_max, None);
+        assert_eq!(cgroup.memory_current, 1048576);
+        assert_eq!(cgroup.cpu_max, None);
+    }
+
+    #[test]
+    fn cgroup_v1() {
+        let root = cgroup_root("v1");
+        let proc_cgroup = "5:pids:/app\n4:memory:/app\n3:cpu,cpuacct:/app\n";
+        let controllers = CgroupControllers::parse(proc_cgroup, &root).unwrap();
+        assert_eq!(
+            controllers.memory,
+            Some(Cgroup::V1(root.join("memory/app")))
+        );
+        assert_eq!(
+            controllers.cpu,
+            Some(Cgroup::V1(root.join("cpu,cpuacct/app")))
+        );
+        let cgroup = cgroup_readings(&controllers);
+        assert_eq!(cgroup.memory_max, Some(268435456));
+        assert_eq!(cgroup.memory_current, 3145728);
+        assert_eq!(cgroup.cpu_max, Some(2.0));
+        assert_eq!(cgroup.nr_throttled, 7);
+        assert_eq!(cgroup.throttled_time, Duration::from_millis(2));
+    }
+
+    #[test]
+    fn cgroup_v1_unlimited() {
+        let root = cgroup_root("v1");
+        let proc_cgroup = "4:memory:/\n3:cpu,cpuacct:/\n";
+        let controllers = CgroupControllers::parse(proc_cgroup, &root).unwrap();
+        let cgroup = cgroup_readings(&controllers);
+        // memory.limit_in_bytes is the largest page-aligned i64, the quota -1
+        assert_eq!(cgroup.memory_max, None);
+        assert_eq!(cgroup.memory_current, 4194304);
+        assert_eq!(cgroup.cpu_max, None);
+    }
+
+    #[test]
+    fn cgroup_hybrid() {
+        let root = cgroup_root("hybrid");
+        let proc_cgroup = "4:memory:/\n1:name=systemd:/\n0::/\n";
+        let controllers = CgroupControllers::parse(proc_cgroup, &root).unwrap();
+        assert_eq!(controllers.memory, Some(Cgroup::V1(root.join("memory"))));
+        assert_eq!(controllers.cpu, Some(Cgroup::V2(root.join("unified"))));
+        let cgroup = cgroup_readings(&controllers);
+        assert_eq!(cgroup.memory_max, None);
+        assert_eq!(cgroup.cpu_max, Some(1.0));
+    }
+
+    #[test]
+    fn cgroup_outside_namespace() {
+        // the host path of the cgroup, not visible from the container
+        let root = cgroup_root("v2");
+        let controllers = CgroupControllers::parse("0::/docker/0123abcd\n", &root).unwrap();
+        assert_eq!(controllers.memory, Some(Cgroup::V2(root.clone())));
+        let root = cgroup_root("v1");
+        let controllers = CgroupControllers::parse("4:memory:/docker/0123abcd\n", &root).unwrap();
+        assert_eq!(controllers.memory, Some(Cgroup::V1(root.join("memory"))));
+        assert_eq!(controllers.cpu, None);
+    }
+
+    #[test]
+    fn cgroup_not_mounted() {
+        let root = cgroup_root("missing");
+        assert!(CgroupControllers::parse("0::/\n", &root).is_none());
+    }
+}
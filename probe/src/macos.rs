@@ -56,5 +56,10 @@ pub(crate) fn get_os_readings() -> ReadingsResult<OsReadings> {
         system_time: Duration::from_secs(rusage.ru_stime.tv_sec as _) + Duration::from_micros(rusage.ru_stime.tv_usec as _),
         minor_fault: rusage.ru_minflt as u64,
         major_fault: rusage.ru_majflt as u64,
+        voluntary_context_switch: rusage.ru_nvcsw as u64,
+        involuntary_context_switch: rusage.ru_nivcsw as u64,
+        threads: 0,
+        // the directory listing holds a descriptor of its own
+        open_fds: std::fs::read_dir("/dev/fd").map(|fds| fds.count().saturating_sub(1)).unwrap_or(0) as u64,
     })
 }
@@ -0,0 +1,280 @@
+use std::fmt;
+use std::panic;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::format::{Column, Kind, Value, ValueType};
+
+/// An f64 that can be shared between threads, stored as its bit pattern in
+/// an AtomicU64.
+#[derive(Default)]
+pub struct AtomicF64(AtomicU64);
+
+impl AtomicF64 {
+    pub fn new(v: f64) -> AtomicF64 {
+        AtomicF64(AtomicU64::new(v.to_bits()))
+    }
+
+    pub fn load(&self, order: Ordering) -> f64 {
+        f64::from_bits(self.0.load(order))
+    }
+
+    pub fn store(&self, v: f64, order: Ordering) {
+        self.0.store(v.to_bits(), order)
+    }
+
+    /// Adds to the current value, returning the previous value.
+    pub fn fetch_add(&self, v: f64, order: Ordering) -> f64 {
+        let mut current = self.0.load(Ordering::Relaxed);
+        loop {
+            let new = (f64::from_bits(current) + v).to_bits();
+            match self
+                .0
+                .compare_exchange_weak(current, new, order, Ordering::Relaxed)
+            {
+                Ok(previous) => return f64::from_bits(previous),
+                Err(previous) => current = previous,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for AtomicF64 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.load(Ordering::Relaxed).fmt(f)
+    }
+}
+
+/// Number of linear sub-buckets per power of two in `Histogram`.
+const SUB_BUCKETS_BITS: u32 = 3;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKETS_BITS;
+const BUCKETS: usize = ((64 - SUB_BUCKETS_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+/// A lock-free latency histogram.
+///
+/// Durations are recorded in nanoseconds in log-linear buckets: each power of
+/// two is split in 8 linear buckets, so reported percentiles are within 12.5%
+/// of the actual value.
+///
+/// When registered on a probe, the histogram is summarized at every line as
+/// p50, p90, p99 and max columns, then reset.
+///
+/// ```rust
+/// let histogram = readings_probe::Histogram::new();
+/// for micros in 1..=100 {
+///     histogram.record(std::time::Duration::from_micros(micros));
+/// }
+/// let [p50, _p90, p99, max] = histogram.take_percentiles();
+/// assert!(p50 >= 50_000 && p50 < 57_000);
+/// assert!(p99 >= 99_000 && p99 <= max);
+/// assert_eq!(max, 100_000);
+/// assert_eq!(histogram.take_percentiles(), [0; 4]);
+/// ```
+pub struct Histogram {
+    buckets: Box<[AtomicU64]>,
+    max: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Histogram {
+        Histogram::new()
+    }
+}
+
+impl Histogram {
+    pub fn new() -> Histogram {
+        Histogram {
+            buckets: (0..BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    /// Records a duration.
+    pub fn record(&self, duration: Duration) {
+        self.record_nanos(duration.as_nanos().min(u64::MAX as u128) as u64)
+    }
+
+    /// Records a duration expressed in nanoseconds.
+    pub fn record_nanos(&self, nanos: u64) {
+        self.buckets[Self::bucket(nanos)].fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(nanos, Ordering::Relaxed);
+    }
+
+    fn bucket(v: u64) -> usize {
+        if v < SUB_BUCKETS {
+            v as usize
+        } else {
+            let exp = 63 - v.leading_zeros();
+            let sub = (v >> (exp - SUB_BUCKETS_BITS)) & (SUB_BUCKETS - 1);
+            ((exp - SUB_BUCKETS_BITS + 1) as u64 * SUB_BUCKETS + sub) as usize
+        }
+    }
+
+    /// Highest value falling in the bucket.
+    fn bucket_high(ix: usize) -> u64 {
+        let ix = ix as u64;
+        if ix < SUB_BUCKETS {
+            ix
+        } else {
+            let shift = ix / SUB_BUCKETS - 1;
+            let low = (SUB_BUCKETS + ix % SUB_BUCKETS) << shift;
+            low + ((1 << shift) - 1)
+        }
+    }
+
+    /// Returns p50, p90, p99 and max in nanoseconds for the values recorded
+    /// since the last call, and resets the histogram.
+    pub fn take_percentiles(&self) -> [u64; 4] {
+        let counts: Vec<u64> = self
+            .buckets
+            .iter()
+            .map(|b| b.swap(0, Ordering::Relaxed))
+            .collect();
+        let max = self.max.swap(0, Ordering::Relaxed);
+        let total: u64 = counts.iter().sum();
+        if total == 0 {
+            return [0; 4];
+        }
+        let mut result = [0, 0, 0, max];
+        for (slot, q) in [0.5, 0.9, 0.99].iter().enumerate() {
+            let rank = ((total as f64 * q).ceil() as u64).max(1);
+            let mut seen = 0;
+            for (ix, count) in counts.iter().enumerate() {
+                seen += count;
+                if seen >= rank {
+                    result[slot] = Self::bucket_high(ix).min(max);
+                    break;
+                }
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Histogram(max: {})", self.max.load(Ordering::Relaxed))
+    }
+}
+
+/// A gauge computed on demand by a callback.
+#[derive(Clone)]
+pub struct GaugeFn(Arc<dyn Fn() -> i64 + Send + Sync>);
+
+impl GaugeFn {
+    pub fn new<F: Fn() -> i64 + Send + Sync + 'static>(f: F) -> GaugeFn {
+        GaugeFn(Arc::new(f))
+    }
+
+    /// Calls the callback, catching its panics.
+    fn eval(&self) -> Result<i64, String> {
+        panic::catch_unwind(panic::AssertUnwindSafe(|| (self.0)())).map_err(|payload| {
+            payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "panicked".to_string())
+        })
+    }
+}
+
+impl fmt::Debug for GaugeFn {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "GaugeFn")
+    }
+}
+
+/// A user-defined metric, as registered on a probe.
+#[derive(Clone, Debug)]
+pub enum Metric {
+    I64(Arc<AtomicI64>),
+    U64(Arc<AtomicU64>),
+    F64(Arc<AtomicF64>),
+    /// A monotonically increasing u64, plotted as a rate.
+    Counter(Arc<AtomicU64>),
+    /// A latency histogram, written as p50, p90, p99 and max columns.
+    Histogram(Arc<Histogram>),
+    /// An i64 gauge computed by a callback at every line.
+    GaugeFn(GaugeFn),
+}
+
+impl Metric {
+    /// Type name.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Metric::I64(_) => "i64",
+            Metric::U64(_) => "u64",
+            Metric::F64(_) => "f64",
+            Metric::Counter(_) => "counter",
+            Metric::Histogram(_) => "histogram",
+            Metric::GaugeFn(_) => "gauge_fn",
+        }
+    }
+
+    /// Column descriptors for this metric.
+    pub(crate) fn columns(&self, name: &str, unit: &str) -> Vec<Column> {
+        match self {
+            Metric::I64(_) | Metric::GaugeFn(_) => {
+                vec![Column::new(name, ValueType::I64, unit, Kind::Gauge, "user")]
+            }
+            Metric::U64(_) => vec![Column::new(name, ValueType::U64, unit, Kind::Gauge, "user")],
+            Metric::F64(_) => vec![Column::new(name, ValueType::F64, unit, Kind::Gauge, "user")],
+            Metric::Counter(_) => {
+                vec![Column::new(
+                    name,
+                    ValueType::U64,
+                    unit,
+                    Kind::Counter,
+                    "user",
+                )]
+            }
+            Metric::Histogram(_) => ["p50", "p90", "p99", "max"]
+                .iter()
+                .map(|q| {
+                    let name = format!("{}.{}", name, q);
+                    Column::new(&name, ValueType::U64, "ns", Kind::Histogram, "user")
+                })
+                .collect(),
+        }
+    }
+
+    /// Appends the current values, resetting histograms.
+    ///
+    /// If a callback panics, a zero is appended in its place and the panic
+    /// message is returned.
+    pub(crate) fn push_values(&self, values: &mut Vec<Value>) -> Result<(), String> {
+        match self {
+            Metric::I64(it) => values.push(Value::I64(it.load(Ordering::Relaxed))),
+            Metric::U64(it) => values.push(Value::U64(it.load(Ordering::Relaxed))),
+            Metric::F64(it) => values.push(Value::F64(it.load(Ordering::Relaxed))),
+            Metric::Counter(it) => values.push(Value::U64(it.load(Ordering::Relaxed))),
+            Metric::Histogram(it) => {
+                values.extend(it.take_percentiles().iter().map(|&v| Value::U64(v)))
+            }
+            Metric::GaugeFn(it) => {
+                let value = it.eval();
+                values.push(Value::I64(*value.as_ref().unwrap_or(&0)));
+                return value.map(|_| ());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A metric as registered on a probe, with its name and unit.
+pub(crate) struct Registered {
+    pub name: String,
+    pub unit: String,
+    pub metric: Metric,
+}
+
+impl Registered {
+    pub fn new(name: &str, metric: Metric) -> Registered {
+        Registered {
+            name: name.to_string(),
+            unit: "-".to_string(),
+            metric,
+        }
+    }
+}
@@ -0,0 +1,330 @@
+//! Software performance counters from the kernel, read with
+//! perf_event_open(2).
+//!
+//! The counters are opened when the probe is created, and each line records
+//! the counts since the previous one.
+//!
+//! The counters are not process-wide: they count the thread creating the
+//! probe and the threads it spawns from then on, directly or not. Threads
+//! started earlier, and the threads they spawn, are not accounted for, so the
+//! probe should be created early in `main`. When the kernel refuses to open
+//! a counter (most likely because of `/proc/sys/kernel/perf_event_paranoid`),
+//! the task clock and page faults are counted in user space only. Context
+//! switches and CPU migrations happen in kernel space, so they are left out of
+//! the recording instead, and the reason is written in the header metadata.
+
+use std::io;
+use std::sync::Mutex;
+
+use crate::format::{Column, Kind, Value, ValueType};
+use crate::{ReadingSource, ReadingsError, ReadingsResult};
+
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+const PERF_COUNT_SW_CPU_MIGRATIONS: u64 = 4;
+
+/// Column name and software event of each counter.
+const EVENTS: &[(&str, u64)] = &[
+    ("task_clock", PERF_COUNT_SW_TASK_CLOCK),
+    ("page_faults", PERF_COUNT_SW_PAGE_FAULTS),
+    ("context_switches", PERF_COUNT_SW_CONTEXT_SWITCHES),
+    ("cpu_migrations", PERF_COUNT_SW_CPU_MIGRATIONS),
+];
+
+struct Counter {
+    name: &'static str,
+    config: u64,
+    fd: i32,
+}
+
+pub(crate) struct PerfCounters {
+    counters: Vec<Counter>,
+    absent: Vec<(&'static str, io::Error)>,
+    /// Counts at the previous sample.
+    last: Mutex<Vec<u64>>,
+}
+
+impl PerfCounters {
+    pub(crate) fn open() -> PerfCounters {
+        PerfCounters::open_with(open_counter)
+    }
+
+    fn open_with(open: impl Fn(u64) -> io::Result<i32>) -> PerfCounters {
+        let mut counters = vec![];
+        let mut absent = vec![];
+        for &(name, config) in EVENTS {
+            match open(config) {
+                Ok(fd) => counters.push(Counter { name, config, fd }),
+                Err(e) => absent.push((name, e)),
+            }
+        }
+        let last = Mutex::new(vec![0; counters.len()]);
+        PerfCounters {
+            counters,
+            absent,
+            last,
+        }
+    }
+}
+
+impl ReadingSource for PerfCounters {
+    /// One "perf_absent" entry per counter that could not be opened.
+    fn metadata(&self) -> Vec<(String, String)> {
+        self.absent
+            .iter()
+            .map(|(name, e)| ("perf_absent".to_string(), format!("{}: {}", name, e)))
+            .collect()
+    }
+
+    fn columns(&self) -> Vec<Column> {
+        self.counters
+            .iter()
+            .map(|c| {
+                if c.config == PERF_COUNT_SW_TASK_CLOCK {
+                    Column::new(c.name, ValueType::F64, "s", Kind::Gauge, "perf")
+                } else {
+                    Column::new(c.name, ValueType::U64, "-", Kind::Gauge, "perf")
+                }
+            })
+            .collect()
+    }
+
+    /// Appends the counts since the previous sample.
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        let mut last = self.last.lock().map_err(|_| ReadingsError::PoisonedProbe)?;
+        for (c, last) in self.counters.iter().zip(last.iter_mut()) {
+            let count = read_counter(c.fd);
+            let delta = count.saturating_sub(*last);
+            *last = count;
+            if c.config == PERF_COUNT_SW_TASK_CLOCK {
+                // in nanoseconds
+                values.push(Value::F64(delta as f64 / 1e9));
+            } else {
+                values.push(Value::U64(delta));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for PerfCounters {
+    fn drop(&mut self) {
+        for c in &self.counters {
+            unsafe {
+                libc::close(c.fd);
+            }
+        }
+    }
+}
+
+/// The first version of perf_event_attr. The kernel considers the fields
+/// added since then as zeroes.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+// PerfEventAttr::flags bits
+const INHERIT: u64 = 1 << 1;
+const EXCLUDE_KERNEL: u64 = 1 << 5;
+const EXCLUDE_HV: u64 = 1 << 6;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open_counter(config: u64) -> io::Result<i32> {
+    open_counter_with(config, |attr| unsafe {
+        // this process, any cpu, no group
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            0 as libc::pid_t,
+            -1 as libc::c_int,
+            -1 as libc::c_int,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    })
+}
+
+/// Opens a counter with the given perf_event_open call, which returns the
+/// file descriptor, or a negative value and sets errno.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn open_counter_with(
+    config: u64,
+    open: impl Fn(&PerfEventAttr) -> libc::c_long,
+) -> io::Result<i32> {
+    let mut attr = PerfEventAttr {
+        kind: PERF_TYPE_SOFTWARE,
+        size: std::mem::size_of::<PerfEventAttr>() as u32,
+        config,
+        flags: INHERIT | EXCLUDE_HV,
+        ..PerfEventAttr::default()
+    };
+    let mut fd = open(&attr);
+    // context switches and migrations would always count zero in user space
+    let user_space = config == PERF_COUNT_SW_TASK_CLOCK || config == PERF_COUNT_SW_PAGE_FAULTS;
+    if fd < 0 && user_space {
+        // a perf_event_paranoid of 2 only allows to count in user space
+        attr.flags |= EXCLUDE_KERNEL;
+        fd = open(&attr);
+    }
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(fd as i32)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn open_counter(_config: u64) -> io::Result<i32> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "perf events are only available on Linux",
+    ))
+}
+
+fn read_counter(fd: i32) -> u64 {
+    let mut count = 0u64;
+    let read = unsafe { libc::read(fd, &mut count as *mut u64 as *mut libc::c_void, 8) };
+    if read == 8 {
+        count
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn kernel_space_is_excluded_on_refusal() {
+        let tried = RefCell::new(vec![]);
+        let fd = open_counter_with(PERF_COUNT_SW_PAGE_FAULTS, |attr| {
+            tried.borrow_mut().push(attr.flags);
+            if attr.flags & EXCLUDE_KERNEL != 0 {
+                7
+            } else {
+                -1
+            }
+        });
+        assert_eq!(fd.unwrap(), 7);
+        assert_eq!(
+            *tried.borrow(),
+            vec![INHERIT | EXCLUDE_HV, INHERIT | EXCLUDE_HV | EXCLUDE_KERNEL]
+        );
+        assert!(open_counter_with(PERF_COUNT_SW_PAGE_FAULTS, |_| -1).is_err());
+
+        // no retry for the counters of kernel space events
+        tried.borrow_mut().clear();
+        let fd = open_counter_with(PERF_COUNT_SW_CONTEXT_SWITCHES, |attr| {
+            tried.borrow_mut().push(attr.flags);
+            -1
+        });
+        assert!(fd.is_err());
+        assert_eq!(*tried.borrow(), vec![INHERIT | EXCLUDE_HV]);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn kernel_counters_are_absent_on_refusal() {
+        let tried = RefCell::new(vec![]);
+        // only counting in user space is allowed
+        let perf = PerfCounters::open_with(|config| {
+            open_counter_with(config, |attr| {
+                tried.borrow_mut().push((attr.config, attr.flags));
+                if attr.flags & EXCLUDE_KERNEL == 0 {
+                    return -1;
+                }
+                let mut fds = [0; 2];
+                assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+                unsafe { libc::close(fds[1]) };
+                fds[0] as libc::c_long
+            })
+        });
+        let names: Vec<String> = perf.columns().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, ["task_clock", "page_faults"]);
+        let metadata = perf.metadata();
+        assert_eq!(metadata.len(), 2);
+        assert_eq!(metadata[0].0, "perf_absent");
+        assert!(metadata[0].1.starts_with("context_switches: "));
+        assert_eq!(metadata[1].0, "perf_absent");
+        assert!(metadata[1].1.starts_with("cpu_migrations: "));
+        let flags = INHERIT | EXCLUDE_HV;
+        assert_eq!(
+            *tried.borrow(),
+            vec![
+                (PERF_COUNT_SW_TASK_CLOCK, flags),
+                (PERF_COUNT_SW_TASK_CLOCK, flags | EXCLUDE_KERNEL),
+                (PERF_COUNT_SW_PAGE_FAULTS, flags),
+                (PERF_COUNT_SW_PAGE_FAULTS, flags | EXCLUDE_KERNEL),
+                (PERF_COUNT_SW_CONTEXT_SWITCHES, flags),
+                (PERF_COUNT_SW_CPU_MIGRATIONS, flags),
+            ]
+        );
+    }
+
+    /// Feeds the counts of an opened counter.
+    fn write_count(fd: i32, count: u64) {
+        let written = unsafe { libc::write(fd, &count as *const u64 as *const libc::c_void, 8) };
+        assert_eq!(written, 8);
+    }
+
+    #[test]
+    fn refused_counters_are_absent() {
+        // counters are read from pipes, fed with cumulative counts
+        let inputs = RefCell::new(vec![]);
+        let perf = PerfCounters::open_with(|config| {
+            if config == PERF_COUNT_SW_PAGE_FAULTS {
+                return Err(io::Error::from(io::ErrorKind::PermissionDenied));
+            }
+            let mut fds = [0; 2];
+            assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+            inputs.borrow_mut().push(fds[1]);
+            Ok(fds[0])
+        });
+        let names: Vec<String> = perf.columns().into_iter().map(|c| c.name).collect();
+        assert_eq!(names, ["task_clock", "context_switches", "cpu_migrations"]);
+        let metadata = perf.metadata();
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[0].0, "perf_absent");
+        assert!(metadata[0].1.starts_with("page_faults: "));
+
+        let inputs = inputs.into_inner();
+        let sample = |counts: [u64; 3]| {
+            for (&fd, &count) in inputs.iter().zip(counts.iter()) {
+                write_count(fd, count);
+            }
+            let mut values = vec![];
+            perf.sample(&mut values).unwrap();
+            values
+        };
+        assert_eq!(
+            sample([2_000_000_000, 5, 1]),
+            [Value::F64(2.0), Value::U64(5), Value::U64(1)]
+        );
+        assert_eq!(
+            sample([2_500_000_000, 12, 1]),
+            [Value::F64(0.5), Value::U64(7), Value::U64(0)]
+        );
+        for fd in inputs {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+}
@@ -0,0 +1,444 @@
+use std::sync::atomic::Ordering::Relaxed;
+
+use crate::format::{Column, Kind, Value, ValueType};
+use crate::{
+    alloc, get_io_readings, get_memory_readings, get_os_readings, ReadingsError, ReadingsResult,
+};
+
+/// A set of columns sampled in every line.
+///
+/// Sources are registered on the `ProbeBuilder`. They are sampled
+/// concurrently by the threads logging events and by the heartbeat, so they
+/// must be cheap and thread-safe.
+///
+/// ```rust
+/// use readings_probe::format::{Column, Kind, Value, ValueType};
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::sync::Arc;
+///
+/// struct Queue(Arc<AtomicUsize>);
+///
+/// impl readings_probe::ReadingSource for Queue {
+///     fn columns(&self) -> Vec<Column> {
+///         vec![Column::new("queue", ValueType::U64, "-", Kind::Gauge, "user")]
+///     }
+///
+///     fn sample(&self, values: &mut Vec<Value>) -> readings_probe::ReadingsResult<()> {
+///         values.push(Value::U64(self.0.load(Ordering::Relaxed) as u64));
+///         Ok(())
+///     }
+/// }
+///
+/// fn main() -> readings_probe::ReadingsResult<()> {
+///     let depth = Arc::new(AtomicUsize::new(0));
+///     let probe = readings_probe::ProbeBuilder::new(std::io::sink())
+///         .source(Queue(depth.clone()))
+///         .build()?;
+///     depth.store(12, Ordering::Relaxed);
+///     probe.log_event("enqueued")?;
+///     Ok(())
+/// }
+/// ```
+pub trait ReadingSource: Send + Sync {
+    /// Describes the columns, in the order `sample` pushes their values.
+    ///
+    /// Called when the probe is built and when the header is written, and
+    /// must return the same columns each time. Columns whose source is
+    /// "user" are plotted with the user-defined metrics.
+    fn columns(&self) -> Vec<Column>;
+
+    /// Appends one value per column. Pushing more or less values fails the
+    /// line with `ReadingsError::SourceValues`.
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()>;
+
+    /// Key/value pairs to add to the header metadata block.
+    fn metadata(&self) -> Vec<(String, String)> {
+        vec![]
+    }
+}
+
+/// The readings built in the probe, in the order of their columns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Builtin {
+    /// Sizes, CPU times, faults, context switches, threads and descriptors
+    /// from the operating system. Enabled by default.
+    Os,
+    /// Allocated and freed bytes, live heap peak and allocator calls from
+    /// `wrap_global_allocator!`. Enabled by default.
+    Alloc,
+    /// I/O counters, Linux only. Enabled by default.
+    Io,
+    /// Cgroup memory usage and CPU throttling, Linux only. Enabled by default.
+    Cgroup,
+    /// Resident memory composition, Linux only. See
+    /// `ProbeBuilder::memory_breakdown`.
+    MemoryBreakdown,
+    /// Pressure stall information, Linux only. See `ProbeBuilder::pressure`.
+    Pressure,
+    /// Software perf_event counters since the previous line, Linux only.
+    /// Enabled by default, but only available with the `perf` feature.
+    Perf,
+}
+
+impl Builtin {
+    pub(crate) const ALL: &'static [Builtin] = &[
+        Builtin::Os,
+        Builtin::Alloc,
+        Builtin::Io,
+        Builtin::Cgroup,
+        Builtin::MemoryBreakdown,
+        Builtin::Pressure,
+        Builtin::Perf,
+    ];
+
+    pub(crate) fn enabled_by_default(self) -> bool {
+        !matches!(self, Builtin::MemoryBreakdown | Builtin::Pressure)
+    }
+
+    /// Instantiates the source, or returns None if its readings are not
+    /// available on this system.
+    pub(crate) fn source(self) -> Option<Box<dyn ReadingSource>> {
+        match self {
+            Builtin::Os => Some(Box::new(OsSource)),
+            Builtin::Alloc => Some(Box::new(AllocSource)),
+            Builtin::Io if matches!(get_io_readings(), Ok(Some(_))) => Some(Box::new(IoSource)),
+            Builtin::Cgroup => CgroupSource::new().map(|s| Box::new(s) as _),
+            Builtin::MemoryBreakdown if matches!(get_memory_readings(), Ok(Some(_))) => {
+                Some(Box::new(MemorySource))
+            }
+            Builtin::Pressure => PressureSource::new().map(|s| Box::new(s) as _),
+            #[cfg(feature = "perf")]
+            Builtin::Perf => Some(Box::new(crate::perf::PerfCounters::open())),
+            _ => None,
+        }
+    }
+}
+
+/// The sources enabled by default.
+pub(crate) fn default_sources() -> Vec<Box<dyn ReadingSource>> {
+    Builtin::ALL
+        .iter()
+        .filter(|b| b.enabled_by_default())
+        .filter_map(|b| b.source())
+        .collect()
+}
+
+fn push_u64(values: &mut Vec<Value>, readings: &[u64]) {
+    values.extend(readings.iter().map(|&v| Value::U64(v)));
+}
+
+struct OsSource;
+
+impl ReadingSource for OsSource {
+    fn columns(&self) -> Vec<Column> {
+        use Kind::*;
+        use ValueType::*;
+        vec![
+            Column::new("vsz", U64, "B", Gauge, "os"),
+            Column::new("rsz", U64, "B", Gauge, "os"),
+            Column::new("rszmax", U64, "B", Gauge, "os"),
+            Column::new("utime", F64, "s", Counter, "os"),
+            Column::new("stime", F64, "s", Counter, "os"),
+            Column::new("minf", U64, "-", Counter, "os"),
+            Column::new("majf", U64, "-", Counter, "os"),
+            Column::new("nvcsw", U64, "-", Counter, "os"),
+            Column::new("nivcsw", U64, "-", Counter, "os"),
+            Column::new("threads", U64, "-", Gauge, "os"),
+            Column::new("fds", U64, "-", Gauge, "os"),
+        ]
+    }
+
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        let usage = get_os_readings()?;
+        push_u64(
+            values,
+            &[
+                usage.virtual_size,
+                usage.resident_size,
+                usage.resident_size_max,
+            ],
+        );
+        values.push(Value::F64(usage.user_time.as_secs_f64()));
+        values.push(Value::F64(usage.system_time.as_secs_f64()));
+        push_u64(
+            values,
+            &[
+                usage.minor_fault,
+                usage.major_fault,
+                usage.voluntary_context_switch,
+                usage.involuntary_context_switch,
+                usage.threads,
+                usage.open_fds,
+            ],
+        );
+        Ok(())
+    }
+}
+
+struct AllocSource;
+
+impl ReadingSource for AllocSource {
+    fn columns(&self) -> Vec<Column> {
+        use Kind::*;
+        use ValueType::*;
+        vec![
+            Column::new("alloc", U64, "B", Counter, "alloc"),
+            Column::new("free", U64, "B", Counter, "alloc"),
+            Column::new("heap_peak", U64, "B", Gauge, "alloc"),
+            Column::new("allocs", U64, "-", Counter, "alloc"),
+            Column::new("deallocs", U64, "-", Counter, "alloc"),
+            Column::new("reallocs", U64, "-", Counter, "alloc"),
+            Column::new("live_allocs", U64, "-", Gauge, "alloc"),
+        ]
+    }
+
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        // deallocations first, so that the live count is not skewed negative
+        let deallocs = alloc::DEALLOCATIONS.load(Relaxed) as u64;
+        let allocs = alloc::ALLOCATIONS.load(Relaxed) as u64;
+        push_u64(
+            values,
+            &[
+                alloc::ALLOCATED.load(Relaxed) as u64,
+                alloc::FREEED.load(Relaxed) as u64,
+                alloc::reset_peak() as u64,
+                allocs,
+                deallocs,
+                alloc::REALLOCATIONS.load(Relaxed) as u64,
+                allocs.saturating_sub(deallocs),
+            ],
+        );
+        Ok(())
+    }
+}
+
+/// Live heap of the thread groups registered with `ProbeBuilder::alloc_group`.
+pub(crate) struct AllocGroupsSource {
+    groups: Vec<(String, usize)>,
+}
+
+impl AllocGroupsSource {
+    pub(crate) fn new(names: &[String]) -> ReadingsResult<AllocGroupsSource> {
+        let groups = names
+            .iter()
+            .map(|name| {
+                alloc::register_group(name)
+                    .map(|ix| (name.clone(), ix))
+                    .ok_or(ReadingsError::TooManyAllocGroups(alloc::MAX_GROUPS))
+            })
+            .collect::<ReadingsResult<_>>()?;
+        Ok(AllocGroupsSource { groups })
+    }
+}
+
+impl ReadingSource for AllocGroupsSource {
+    fn columns(&self) -> Vec<Column> {
+        self.groups
+            .iter()
+            .map(|(name, _)| {
+                Column::new(
+                    &format!("heap.{}", name),
+                    ValueType::I64,
+                    "B",
+                    Kind::Gauge,
+                    "alloc_group",
+                )
+            })
+            .collect()
+    }
+
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        values.extend(
+            self.groups
+                .iter()
+                .map(|&(_, ix)| Value::I64(alloc::group_live(ix) as i64)),
+        );
+        Ok(())
+    }
+}
+
+// The sources below were found readable when the probe was created: keep
+// the rows well formed with zeros if they are not readable anymore.
+
+struct IoSource;
+
+impl ReadingSource for IoSource {
+    fn columns(&self) -> Vec<Column> {
+        use Kind::*;
+        use ValueType::*;
+        vec![
+            Column::new("rchar", U64, "B", Counter, "io"),
+            Column::new("wchar", U64, "B", Counter, "io"),
+            Column::new("read_bytes", U64, "B", Counter, "io"),
+            Column::new("write_bytes", U64, "B", Counter, "io"),
+            Column::new("syscr", U64, "-", Counter, "io"),
+            Column::new("syscw", U64, "-", Counter, "io"),
+        ]
+    }
+
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        let io = get_io_readings().ok().flatten().unwrap_or_default();
+        push_u64(
+            values,
+            &[
+                io.rchar,
+                io.wchar,
+                io.read_bytes,
+                io.write_bytes,
+                io.syscr,
+                io.syscw,
+            ],
+        );
+        Ok(())
+    }
+}
+
+/// Cgroup usage, from the controller directories found when the source is
+/// created. The limits are read once and written in the header.
+struct CgroupSource {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    controllers: crate::linux::CgroupControllers,
+    limits: crate::CgroupReadings,
+}
+
+impl CgroupSource {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn new() -> Option<CgroupSource> {
+        let controllers = crate::linux::CgroupControllers::find().ok().flatten()?;
+        let mut limits = crate::CgroupReadings::default();
+        controllers.limits(&mut limits);
+        Some(CgroupSource {
+            controllers,
+            limits,
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn new() -> Option<CgroupSource> {
+        None
+    }
+}
+
+impl ReadingSource for CgroupSource {
+    fn columns(&self) -> Vec<Column> {
+        use Kind::*;
+        use ValueType::*;
+        vec![
+            Column::new("cgroup_mem", U64, "B", Gauge, "cgroup"),
+            Column::new("nr_throttled", U64, "-", Counter, "cgroup"),
+            Column::new("throttled", F64, "s", Counter, "cgroup"),
+        ]
+    }
+
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        #[allow(unused_mut)]
+        let mut cgroup = crate::CgroupReadings::default();
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        self.controllers.usage(&mut cgroup);
+        push_u64(values, &[cgroup.memory_current, cgroup.nr_throttled]);
+        values.push(Value::F64(cgroup.throttled_time.as_secs_f64()));
+        Ok(())
+    }
+
+    fn metadata(&self) -> Vec<(String, String)> {
+        let mut metadata = vec![];
+        if let Some(max) = self.limits.memory_max {
+            metadata.push(("cgroup_memory_max".to_string(), max.to_string()));
+        }
+        if let Some(max) = self.limits.cpu_max {
+            metadata.push(("cgroup_cpu_max".to_string(), max.to_string()));
+        }
+        metadata
+    }
+}
+
+struct MemorySource;
+
+impl ReadingSource for MemorySource {
+    fn columns(&self) -> Vec<Column> {
+        use Kind::*;
+        use ValueType::*;
+        vec![
+            Column::new("pss", U64, "B", Gauge, "mem"),
+            Column::new("anon", U64, "B", Gauge, "mem"),
+            Column::new("file", U64, "B", Gauge, "mem"),
+            Column::new("shmem", U64, "B", Gauge, "mem"),
+            Column::new("swap", U64, "B", Gauge, "mem"),
+            Column::new("locked", U64, "B", Gauge, "mem"),
+        ]
+    }
+
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        let mem = get_memory_readings().ok().flatten().unwrap_or_default();
+        push_u64(
+            values,
+            &[
+                mem.pss,
+                mem.anonymous,
+                mem.file,
+                mem.shmem,
+                mem.swap,
+                mem.locked,
+            ],
+        );
+        Ok(())
+    }
+}
+
+/// Pressure stall information, from the files found when the source is
+/// created.
+struct PressureSource {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    files: crate::linux::PressureFiles,
+}
+
+impl PressureSource {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn new() -> Option<PressureSource> {
+        let files = crate::linux::PressureFiles::find().ok().flatten()?;
+        Some(PressureSource { files })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    fn new() -> Option<PressureSource> {
+        None
+    }
+}
+
+impl ReadingSource for PressureSource {
+    fn columns(&self) -> Vec<Column> {
+        let mut columns = vec![];
+        for resource in &["cpu", "mem", "io"] {
+            for stall in &["some", "full"] {
+                let name = format!("{}_{}", resource, stall);
+                columns.extend(vec![
+                    Column::new(
+                        &format!("{}_avg10", name),
+                        ValueType::F64,
+                        "%",
+                        Kind::Gauge,
+                        "psi",
+                    ),
+                    Column::new(&name, ValueType::F64, "s", Kind::Counter, "psi"),
+                ]);
+            }
+        }
+        columns
+    }
+
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let psi = self.files.read();
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let psi = crate::PressureReadings::default();
+        for pressure in &[psi.cpu, psi.memory, psi.io] {
+            values.extend(vec![
+                Value::F64(pressure.some_avg10),
+                Value::F64(pressure.some_total.as_secs_f64()),
+                Value::F64(pressure.full_avg10),
+                Value::F64(pressure.full_total.as_secs_f64()),
+            ]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,69 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::format::SpanEdge;
+use crate::{Probe, ReadingsResult};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Guard on an open span, returned by `Probe::span`.
+///
+/// The span ends when the guard is dropped. Spans opened on the same thread
+/// while a guard is alive are nested into it, so the guard must be dropped
+/// on the thread that created it.
+///
+/// ```rust
+/// fn main() -> readings_probe::ReadingsResult<()> {
+///     let probe = readings_probe::ProbeBuilder::new(std::io::sink()).build()?;
+///     {
+///         let _load = probe.span("load_model")?;
+///         let _weights = probe.span("read_weights")?;
+///     }
+///     Ok(())
+/// }
+/// ```
+pub struct SpanGuard {
+    probe: Probe,
+    name: String,
+    id: u64,
+    parent: Option<u64>,
+    // the guard manipulates the current thread span stack
+    _not_send: PhantomData<*const ()>,
+}
+
+impl SpanGuard {
+    pub(crate) fn begin(probe: &Probe, name: &str) -> ReadingsResult<SpanGuard> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let parent = CURRENT.with(|current| current.get());
+        // no guard, hence no end record, for a span whose begin failed
+        probe.write_span(name, SpanEdge::Begin { id, parent })?;
+        CURRENT.with(|current| current.set(Some(id)));
+        Ok(SpanGuard {
+            probe: probe.clone(),
+            name: name.to_string(),
+            id,
+            parent,
+            _not_send: PhantomData,
+        })
+    }
+
+    /// Identifier of the span, unique in the process.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.parent));
+        let edge = SpanEdge::End { id: self.id };
+        if let Err(e) = self.probe.write_span(&self.name, edge) {
+            eprintln!("{:?}", e);
+        }
+    }
+}
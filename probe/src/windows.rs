@@ -48,6 +48,10 @@ pub(crate) fn get_os_readings() -> ReadingsResult<OsReadings> {
             system_time,
             minor_fault,
             major_fault: 0,
+            voluntary_context_switch: 0,
+            involuntary_context_switch: 0,
+            threads: 0,
+            open_fds: 0,
         };
 
         Ok(usage)
@@ -0,0 +1,204 @@
+use std::cmp;
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+use crossbeam_queue::ArrayQueue;
+
+use crate::format::{Encoder, Header, Row, ThreadSample, Value};
+use crate::{ReadingsError, ReadingsResult};
+
+/// How long the writer thread sleeps when it is not woken up by a new sample.
+const IDLE: time::Duration = time::Duration::from_millis(100);
+
+pub(crate) enum Message {
+    Header(Header),
+    Row(Row),
+    Threads(ThreadSample),
+}
+
+impl Message {
+    /// Time of the sample. The header comes first.
+    fn time(&self) -> f64 {
+        match self {
+            Message::Header(_) => f64::NEG_INFINITY,
+            Message::Row(row) => row.values.first().map(Value::as_f64).unwrap_or(0.0),
+            Message::Threads(sample) => sample.time,
+        }
+    }
+}
+
+struct Shared {
+    queue: ArrayQueue<Message>,
+    dropped: AtomicU64,
+    closed: AtomicBool,
+    /// Samples timestamped but not queued yet.
+    in_flight: AtomicUsize,
+}
+
+/// Serializes samples on a dedicated thread.
+///
+/// Samples are handed over through a bounded lock-free queue, so that
+/// `push` never waits on I/O. When the queue is full, the sample is
+/// discarded and accounted for in a dropped record.
+///
+/// Concurrent samples may be queued out of time order. The writer thread
+/// sorts them, and only writes the ones timestamped before a moment when no
+/// sample was in flight (see `sampling`): later ones could still be preceded
+/// by a sample being taken.
+pub(crate) struct Writer {
+    shared: Arc<Shared>,
+    thread: thread::Thread,
+    handle: Mutex<Option<thread::JoinHandle<ReadingsResult<()>>>>,
+}
+
+impl Writer {
+    pub(crate) fn spawn(
+        writer: Box<dyn io::Write + Send>,
+        encoder: Encoder,
+        capacity: usize,
+        origin: time::Instant,
+    ) -> Writer {
+        let shared = Arc::new(Shared {
+            queue: ArrayQueue::new(capacity.max(1)),
+            dropped: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            in_flight: AtomicUsize::new(0),
+        });
+        let handle = {
+            let shared = shared.clone();
+            thread::Builder::new()
+                .name("readings-writer".to_string())
+                .spawn(move || run(&shared, io::BufWriter::new(writer), encoder, origin))
+                .expect("Failed to spawn readings writer thread")
+        };
+        Writer {
+            shared,
+            thread: handle.thread().clone(),
+            handle: Mutex::new(Some(handle)),
+        }
+    }
+
+    /// Marks a sample as in flight until the returned guard is dropped. The
+    /// sample must be timestamped and queued while the guard is alive.
+    pub(crate) fn sampling(&self) -> Sampling<'_> {
+        self.shared.in_flight.fetch_add(1, Ordering::SeqCst);
+        Sampling(self)
+    }
+
+    /// Queues a message for the writer thread, without blocking.
+    pub(crate) fn push(&self, message: Message) {
+        if self.shared.closed.load(Ordering::Acquire) {
+            return;
+        }
+        if self.shared.queue.push(message).is_err() {
+            self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        self.thread.unpark();
+    }
+
+    /// Writes out everything queued so far and terminates the writer thread.
+    ///
+    /// Messages pushed afterwards are ignored. Returns the first I/O error
+    /// met by the writer thread, if any.
+    pub(crate) fn close(&self) -> ReadingsResult<()> {
+        self.shared.closed.store(true, Ordering::Release);
+        self.thread.unpark();
+        let handle = self
+            .handle
+            .lock()
+            .map_err(|_| ReadingsError::PoisonedProbe)?
+            .take();
+        match handle {
+            Some(handle) => handle.join().map_err(|_| ReadingsError::WriterPanicked)?,
+            None => Ok(()),
+        }
+    }
+}
+
+/// A sample in flight, see `Writer::sampling`.
+pub(crate) struct Sampling<'a>(&'a Writer);
+
+impl Drop for Sampling<'_> {
+    fn drop(&mut self) {
+        if self.0.shared.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // the rows held back can be written
+            self.0.thread.unpark();
+        }
+    }
+}
+
+fn run<W: Write>(
+    shared: &Shared,
+    mut writer: W,
+    mut encoder: Encoder,
+    origin: time::Instant,
+) -> ReadingsResult<()> {
+    let mut result = Ok(());
+    let mut header_written = false;
+    // drained messages, held back until the samples taken before them are
+    // queued
+    let mut pending: Vec<Message> = vec![];
+    loop {
+        // read before draining, so that everything pushed before close() is
+        // written out
+        let closed = shared.closed.load(Ordering::Acquire);
+        // with no sample in flight, the samples timestamped so far are all
+        // in the queue
+        let now = origin.elapsed().as_secs_f64();
+        let settled = if shared.in_flight.load(Ordering::SeqCst) == 0 {
+            now
+        } else {
+            f64::NEG_INFINITY
+        };
+        while let Some(message) = shared.queue.pop() {
+            pending.push(message);
+        }
+        // a stable sort, keeping the queue order of equal times
+        pending.sort_by(|a, b| {
+            a.time()
+                .partial_cmp(&b.time())
+                .unwrap_or(cmp::Ordering::Equal)
+        });
+        // give up on the order rather than hold back more than the queue
+        let ready = if closed || pending.len() > shared.queue.capacity() {
+            pending.len()
+        } else {
+            pending.iter().take_while(|m| m.time() <= settled).count()
+        };
+        let mut written = ready > 0;
+        for message in pending.drain(..ready) {
+            if result.is_err() {
+                continue;
+            }
+            result = match message {
+                Message::Header(header) => {
+                    header_written = true;
+                    encoder.write_header(&mut writer, &header)
+                }
+                Message::Row(row) => encoder.write_row(&mut writer, &row),
+                Message::Threads(sample) => encoder.write_threads(&mut writer, &sample),
+            }
+            .map_err(ReadingsError::from);
+        }
+        if header_written {
+            let dropped = shared.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 && result.is_ok() {
+                written = true;
+                result = encoder
+                    .write_dropped(&mut writer, dropped)
+                    .map_err(ReadingsError::from);
+            }
+        }
+        if written && result.is_ok() {
+            result = writer.flush().map_err(ReadingsError::from);
+        }
+        if closed {
+            return result;
+        }
+        thread::park_timeout(IDLE);
+    }
+}
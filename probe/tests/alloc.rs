@@ -0,0 +1,109 @@
+//! Checks the accounting of the instrumented allocator.
+//!
+//! Everything runs in a single test so that no other test thread allocates
+//! while the counters are compared.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::Ordering::Relaxed;
+
+use readings_probe::alloc::{
+    measure, ALLOCATED, ALLOCATIONS, DEALLOCATIONS, FREEED, REALLOCATIONS,
+};
+
+readings_probe::instrumented_allocator!();
+
+fn live_bytes() -> isize {
+    ALLOCATED.load(Relaxed) as isize - FREEED.load(Relaxed) as isize
+}
+
+fn live_allocs() -> isize {
+    ALLOCATIONS.load(Relaxed) as isize - DEALLOCATIONS.load(Relaxed) as isize
+}
+
+#[test]
+fn live_heap_is_exact() {
+    let bytes = live_bytes();
+    let allocs = live_allocs();
+    let reallocs = REALLOCATIONS.load(Relaxed);
+
+    // growth goes through realloc
+    let mut v: Vec<u8> = Vec::with_capacity(16);
+    assert_eq!(live_bytes() - bytes, 16);
+    for i in 0..1000 {
+        v.push(i as u8);
+    }
+    assert_eq!(live_bytes() - bytes, v.capacity() as isize);
+    assert!(REALLOCATIONS.load(Relaxed) > reallocs);
+    assert_eq!(live_allocs() - allocs, 1);
+
+    v.truncate(10);
+    v.shrink_to_fit();
+    assert_eq!(live_bytes() - bytes, 10);
+    drop(v);
+    assert_eq!(live_bytes(), bytes);
+    assert_eq!(live_allocs(), allocs);
+
+    // vec! of zeroes uses alloc_zeroed
+    let zeroes = vec![0u64; 4096];
+    assert_eq!(live_bytes() - bytes, 4096 * 8);
+    assert_eq!(live_allocs() - allocs, 1);
+    drop(zeroes);
+    assert_eq!(live_bytes(), bytes);
+
+    unsafe {
+        let layout = Layout::from_size_align(100, 8).unwrap();
+        let ptr = A.alloc_zeroed(layout);
+        assert!(std::slice::from_raw_parts(ptr, 100).iter().all(|&b| b == 0));
+        assert_eq!(live_bytes() - bytes, 100);
+        let ptr = A.realloc(ptr, layout, 300);
+        assert_eq!(live_bytes() - bytes, 300);
+        let layout = Layout::from_size_align(300, 8).unwrap();
+        let ptr = A.realloc(ptr, layout, 50);
+        assert_eq!(live_bytes() - bytes, 50);
+        A.dealloc(ptr, Layout::from_size_align(50, 8).unwrap());
+    }
+    assert_eq!(live_bytes(), bytes);
+    assert_eq!(live_allocs(), allocs);
+
+    // a short spike is kept as the peak until the next reset
+    readings_probe::alloc::reset_peak();
+    drop(vec![1u8; 1 << 20]);
+    assert!(readings_probe::alloc::reset_peak() >= bytes as usize + (1 << 20));
+    assert!(readings_probe::alloc::reset_peak() < bytes as usize + (1 << 20));
+
+    // measures are exact on the current thread while another one allocates
+    let stop = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let noise = {
+        let stop = stop.clone();
+        std::thread::spawn(move || {
+            while !stop.load(Relaxed) {
+                drop(vec![0u8; 1000]);
+            }
+        })
+    };
+    let (v, stats) = measure(|| {
+        let mut v: Vec<u8> = Vec::with_capacity(16);
+        v.extend(0..100);
+        drop(vec![0u8; 4000]);
+        v
+    });
+    assert_eq!(stats.allocations, 2);
+    assert_eq!(stats.deallocations, 1);
+    assert!(stats.reallocations > 0);
+    assert_eq!(stats.allocated - stats.freed, v.capacity());
+    assert_eq!(stats.peak, v.capacity().max(16) + 4000);
+
+    // nested measures
+    let ((_, inner), outer) = measure(|| {
+        let a = vec![0u8; 100];
+        let inner = measure(|| drop(vec![0u8; 300]));
+        drop(a);
+        inner
+    });
+    assert_eq!(inner.peak, 300);
+    assert_eq!(outer.peak, 400);
+    assert_eq!(outer.allocations, 2);
+
+    stop.store(true, Relaxed);
+    noise.join().unwrap();
+}
@@ -0,0 +1,63 @@
+//! Checks the attribution of the live heap to thread groups.
+
+use std::io::BufReader;
+use std::thread;
+
+readings_probe::instrumented_allocator!();
+
+#[test]
+fn live_heap_by_group() -> readings_probe::ReadingsResult<()> {
+    let path = std::env::temp_dir().join(format!("alloc_groups.{}.out", std::process::id()));
+    let probe = readings_probe::ProbeBuilder::new(std::fs::File::create(&path)?)
+        .alloc_group("worker")
+        .alloc_group("pool")
+        .start()?;
+
+    // by name: the vector outlives the thread, and is freed by the main one
+    let kept = thread::Builder::new()
+        .name("worker-1".to_string())
+        .spawn(|| vec![1u8; 100_000])?
+        .join()
+        .unwrap();
+    thread::Builder::new()
+        .name("worker-2".to_string())
+        .spawn(|| drop(vec![1u8; 1_000_000]))?
+        .join()
+        .unwrap();
+
+    // by explicit registration
+    let pooled = thread::spawn(|| {
+        assert!(readings_probe::alloc::join_group("pool"));
+        vec![1u8; 50_000]
+    })
+    .join()
+    .unwrap();
+
+    probe.log_event("allocated")?;
+    drop(kept);
+    drop(pooled);
+    probe.log_event("freed")?;
+    probe.finish()?;
+
+    let rec = readings_probe::format::read(BufReader::new(std::fs::File::open(&path)?))?;
+    std::fs::remove_file(&path)?;
+    let worker = rec.header.column("heap.worker").unwrap();
+    let pool = rec.header.column("heap.pool").unwrap();
+    let row = |event: &str| {
+        rec.rows
+            .iter()
+            .find(|r| r.event.as_deref() == Some(event))
+            .unwrap()
+    };
+    // allow for the few small blocks threads allocate or free themselves
+    let allocated = row("allocated");
+    assert!((99_000..101_000).contains(&(allocated.values[worker].as_f64() as i64)));
+    assert!((49_000..51_000).contains(&(allocated.values[pool].as_f64() as i64)));
+    // freed from the main thread, in no group
+    let freed = row("freed");
+    assert_eq!(
+        freed.values[worker].as_f64(),
+        allocated.values[worker].as_f64()
+    );
+    Ok(())
+}
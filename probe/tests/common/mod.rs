@@ -0,0 +1,63 @@
+//! Helpers shared by the integration tests.
+
+// each test crate uses its own subset of the helpers
+#![allow(dead_code)]
+
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex};
+
+use readings_probe::format::{self, Recording};
+use readings_probe::ReadingsResult;
+
+struct State {
+    open: bool,
+    bytes: Vec<u8>,
+}
+
+/// An in-memory output. A closed output blocks writes until it is opened.
+#[derive(Clone)]
+pub struct Output(Arc<(Mutex<State>, Condvar)>);
+
+impl Default for Output {
+    fn default() -> Output {
+        Output(Arc::new((
+            Mutex::new(State {
+                open: true,
+                bytes: vec![],
+            }),
+            Condvar::new(),
+        )))
+    }
+}
+
+impl Output {
+    pub fn closed() -> Output {
+        let output = Output::default();
+        (output.0).0.lock().unwrap().open = false;
+        output
+    }
+
+    pub fn open(&self) {
+        (self.0).0.lock().unwrap().open = true;
+        (self.0).1.notify_all();
+    }
+
+    pub fn recording(&self) -> ReadingsResult<Recording> {
+        format::read(&*(self.0).0.lock().unwrap().bytes)
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = (self.0).0.lock().unwrap();
+        while !state.open {
+            state = (self.0).1.wait(state).unwrap();
+        }
+        state.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
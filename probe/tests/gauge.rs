@@ -0,0 +1,46 @@
+//! Checks that a panicking gauge is flagged on heartbeat lines.
+
+mod common;
+
+use std::time::Duration;
+
+use common::Output;
+use readings_probe::format::{Encoding, FieldValue, Value};
+use readings_probe::{ProbeBuilder, ReadingsResult};
+
+fn panicking_gauge(encoding: Encoding) -> ReadingsResult<()> {
+    let output = Output::default();
+    let mut builder = ProbeBuilder::new(output.clone())
+        .encoding(encoding)
+        .heartbeat(Duration::from_millis(5));
+    builder.register_gauge_fn("broken", || panic!("boom"));
+    let probe = builder.start()?;
+    std::thread::sleep(Duration::from_millis(50));
+    probe.finish()?;
+
+    let rec = output.recording()?;
+    let broken = rec.header.column("broken").unwrap();
+    let heartbeats: Vec<_> = rec.rows.iter().filter(|r| r.event.is_none()).collect();
+    assert!(!heartbeats.is_empty());
+    for row in heartbeats {
+        assert_eq!(row.values[broken], Value::I64(0));
+        assert_eq!(
+            row.fields,
+            [(
+                "error".to_string(),
+                FieldValue::Str("broken panicked: boom".to_string())
+            )]
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn panicking_gauge_text() -> ReadingsResult<()> {
+    panicking_gauge(Encoding::Text)
+}
+
+#[test]
+fn panicking_gauge_binary() -> ReadingsResult<()> {
+    panicking_gauge(Encoding::Binary)
+}
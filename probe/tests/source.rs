@@ -0,0 +1,33 @@
+//! Checks that sources push one value per column.
+
+use readings_probe::format::{Column, Kind, Value, ValueType};
+use readings_probe::{ProbeBuilder, ReadingSource, ReadingsError, ReadingsResult};
+
+/// A source declaring two columns, but pushing a single value.
+struct Short;
+
+impl ReadingSource for Short {
+    fn columns(&self) -> Vec<Column> {
+        vec![
+            Column::new("first", ValueType::U64, "-", Kind::Gauge, "user"),
+            Column::new("second", ValueType::U64, "-", Kind::Gauge, "user"),
+        ]
+    }
+
+    fn sample(&self, values: &mut Vec<Value>) -> ReadingsResult<()> {
+        values.push(Value::U64(1));
+        Ok(())
+    }
+}
+
+#[test]
+fn missing_values_fail_the_line() -> ReadingsResult<()> {
+    let probe = ProbeBuilder::new(std::io::sink()).source(Short).start()?;
+    match probe.log_event("short") {
+        Err(ReadingsError::SourceValues { expected, got }) => {
+            assert_eq!((expected, got), (2, 1))
+        }
+        other => panic!("expected a SourceValues error, got {:?}", other),
+    }
+    Ok(())
+}
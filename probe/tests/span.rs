@@ -0,0 +1,104 @@
+//! Checks the span records and their nesting.
+
+mod common;
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use common::Output;
+use readings_probe::format::{Column, SpanEdge, Value};
+use readings_probe::{ProbeBuilder, ReadingSource, ReadingsError, ReadingsResult};
+
+/// A source failing its next sample when `fail` is set.
+struct Flaky(Arc<AtomicBool>);
+
+impl ReadingSource for Flaky {
+    fn columns(&self) -> Vec<Column> {
+        vec![]
+    }
+
+    fn sample(&self, _values: &mut Vec<Value>) -> ReadingsResult<()> {
+        if self.0.swap(false, Ordering::Relaxed) {
+            Err(ReadingsError::Format("flaky".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn nested_spans() -> ReadingsResult<()> {
+    let output = Output::default();
+    let fail = Arc::new(AtomicBool::new(false));
+    let probe = ProbeBuilder::new(output.clone())
+        .source(Flaky(fail.clone()))
+        .start()?;
+    let (outer, inner, sibling) = {
+        let outer = probe.span("outer")?;
+        let inner = {
+            let inner = probe.span("inner")?;
+            let _leaf = probe.span("leaf")?;
+            inner.id()
+        };
+        // a span whose begin failed has no end, and is not the parent of the
+        // next one
+        fail.store(true, Ordering::Relaxed);
+        assert!(probe.span("failed").is_err());
+        let sibling = probe.span("sibling")?.id();
+        (outer.id(), inner, sibling)
+    };
+    probe.finish()?;
+
+    let rec = output.recording()?;
+    let spans = rec
+        .rows
+        .iter()
+        .filter_map(|r| Some((r.event.as_deref()?, r.span?)))
+        .collect::<Vec<_>>();
+    assert_eq!(spans.len(), 8);
+    assert_eq!(
+        spans[0],
+        (
+            "outer",
+            SpanEdge::Begin {
+                id: outer,
+                parent: None
+            }
+        )
+    );
+    assert_eq!(
+        spans[1],
+        (
+            "inner",
+            SpanEdge::Begin {
+                id: inner,
+                parent: Some(outer)
+            }
+        )
+    );
+    let leaf = match spans[2] {
+        (
+            "leaf",
+            SpanEdge::Begin {
+                id,
+                parent: Some(parent),
+            },
+        ) if parent == inner => id,
+        other => panic!("unexpected {:?}", other),
+    };
+    assert_eq!(spans[3], ("leaf", SpanEdge::End { id: leaf }));
+    assert_eq!(spans[4], ("inner", SpanEdge::End { id: inner }));
+    assert_eq!(
+        spans[5],
+        (
+            "sibling",
+            SpanEdge::Begin {
+                id: sibling,
+                parent: Some(outer)
+            }
+        )
+    );
+    assert_eq!(spans[6], ("sibling", SpanEdge::End { id: sibling }));
+    assert_eq!(spans[7], ("outer", SpanEdge::End { id: outer }));
+    Ok(())
+}
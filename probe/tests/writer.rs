@@ -0,0 +1,53 @@
+//! Checks the rows handed over to the writer thread.
+
+mod common;
+
+use std::thread;
+
+use common::Output;
+use readings_probe::ProbeBuilder;
+
+#[test]
+fn concurrent_events_are_written_in_time_order() -> readings_probe::ReadingsResult<()> {
+    let output = Output::default();
+    let probe = ProbeBuilder::new(output.clone()).buffer(1 << 16).start()?;
+    let threads = (0..8)
+        .map(|_| {
+            let probe = probe.clone();
+            thread::spawn(move || {
+                for _ in 0..2000 {
+                    probe.log_event("event").unwrap();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for t in threads {
+        t.join().unwrap();
+    }
+    probe.finish()?;
+    let rec = output.recording()?;
+    assert_eq!(rec.rows.len() as u64 + rec.dropped, 16001);
+    let times = rec
+        .rows
+        .iter()
+        .map(|r| r.values[0].as_f64())
+        .collect::<Vec<_>>();
+    assert!(times.windows(2).all(|w| w[0] <= w[1]));
+    Ok(())
+}
+
+#[test]
+fn samples_are_dropped_when_the_writer_lags() -> readings_probe::ReadingsResult<()> {
+    let output = Output::closed();
+    let probe = ProbeBuilder::new(output.clone()).buffer(1).start()?;
+    for _ in 0..100 {
+        probe.log_event("event")?;
+    }
+    output.open();
+    probe.finish()?;
+    let rec = output.recording()?;
+    assert!(rec.dropped > 0);
+    // every event, plus the "stopped" line, is either written or dropped
+    assert_eq!(rec.rows.len() as u64 + rec.dropped, 101);
+    Ok(())
+}